@@ -1,3 +1,6 @@
+// The lexer module generated by lrlex should compile without warnings on current editions.
+#![deny(warnings)]
+
 use std::io::{self, BufRead, Write};
 
 #[macro_use] extern crate lrlex;
@@ -0,0 +1,9 @@
+extern crate lrlex;
+
+use lrlex::LexerBuilder;
+
+fn main() {
+    // Every rule in `toks.l` is simple enough for lrlex to generate a hand-rolled scanner for it
+    // (the `lex` function in the generated module) as well as the usual `lexerdef`.
+    LexerBuilder::<u8>::new().scanner(true).process_file_in_src("toks.l").unwrap();
+}
@@ -0,0 +1,43 @@
+// The lexer module generated by lrlex should compile without warnings on current editions.
+#![deny(warnings)]
+
+use std::io::{self, BufRead, Write};
+
+#[macro_use] extern crate lrlex;
+
+// Using `lrlex_mod!` brings the lexer for `toks.l`, including its hand-rolled scanner `lex`,
+// into scope.
+lrlex_mod!(toks_l);
+
+fn main() {
+    let stdin = io::stdin();
+    loop {
+        print!(">>> ");
+        io::stdout().flush().ok();
+        match stdin.lock().lines().next() {
+            Some(Ok(ref l)) => {
+                // The scanner returns the same lexemes as `toks_l::lexerdef().lexer(l)` would, or
+                // the byte offset of the first input it could not lex.
+                match toks_l::lex(l) {
+                    Ok(lexemes) => println!("{:?}", lexemes),
+                    Err(i) => println!("Lexing error at offset {}", i)
+                }
+            },
+            _ => break
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::toks_l;
+
+    #[test]
+    fn test_scanner_matches_lexer() {
+        let lexerdef = toks_l::lexerdef();
+        for s in &["", "a + 12", "x++ \"a b\" +++ 3", "abc12 _x\t\"\"", "a $ b", "\"abc",
+                   "a @ b"] {
+            assert_eq!(toks_l::lex(s), lexerdef.lexer(s).lexemes().map_err(|e| e.idx()), "{}", s);
+        }
+    }
+}
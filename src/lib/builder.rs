@@ -35,19 +35,708 @@ use std::convert::{AsRef, TryFrom};
 use std::env::{current_dir, var};
 use std::error::Error;
 use std::fmt::Debug;
-use std::fs::{File, read_to_string};
+use std::fs::{File, read, read_to_string};
+use std::hash::Hasher;
 use std::io::Write;
+use std::mem::size_of;
 use std::path::{Path, PathBuf};
 
 use typename::TypeName;
 
-use lexer::LexerDef;
-use parser::parse_lex;
+use {LexBuildError, LexErrorKind};
+use lexer::{FnvHasher, LexerDef};
+use parser::parse_lex_with_nest_limit;
 
 const LEX_SUFFIX: &str = "_l";
 const LEX_FILE_EXT: &str = "l";
+const LEX_FN_NAME: &str = "lexerdef";
+const SCANNER_FN_NAME: &str = "lex";
+const RULE_REGEXES_NAME: &str = "RULE_REGEXES";
 const RUST_FILE_EXT: &str = "rs";
 
+/// The rule names returned by [`process_file`](fn.process_file.html) and friends, as
+/// (*missing_from_lexer*, *missing_from_parser*).
+type MissingNames = (Option<HashSet<String>>, Option<HashSet<String>>);
+
+/// A `LexerBuilder` allows one to specify the criteria for building a statically generated
+/// lexer. The free functions [`process_file`](fn.process_file.html) and
+/// [`process_file_in_src`](fn.process_file_in_src.html) are convenience wrappers around a
+/// `LexerBuilder` with default settings.
+pub struct LexerBuilder<TokId> {
+    rule_ids_map: Option<HashMap<String, TokId>>,
+    id_base: usize,
+    runtime_rule_ids: bool,
+    visitor: bool,
+    tok_names: bool,
+    scanner: bool,
+    check_equivalent_rules: bool,
+    nest_limit: Option<u32>,
+    cfg: Option<String>,
+    span_info: bool,
+    default_type: Option<String>,
+    rule_regexes: bool,
+    hashed_ids: bool
+}
+
+impl<TokId> LexerBuilder<TokId>
+    where TokId: Copy + Debug + Eq + TryFrom<usize> + TypeName
+{
+    /// Create a new `LexerBuilder`.
+    pub fn new() -> Self {
+        LexerBuilder{rule_ids_map: None,
+                     id_base: 0,
+                     runtime_rule_ids: false,
+                     visitor: false,
+                     tok_names: false,
+                     scanner: false,
+                     check_equivalent_rules: false,
+                     nest_limit: None,
+                     cfg: None,
+                     span_info: false,
+                     default_type: None,
+                     rule_regexes: false,
+                     hashed_ids: false}
+    }
+
+    /// Set the rule IDs map to `rule_ids_map`. See [`process_file`](fn.process_file.html) for
+    /// the semantics of this map.
+    pub fn rule_ids_map(mut self, rule_ids_map: HashMap<String, TokId>) -> Self {
+        self.rule_ids_map = Some(rule_ids_map);
+        self
+    }
+
+    /// Give rules consecutive IDs starting at `id_base` (which defaults to 0), in the order they
+    /// are defined. This is useful to avoid clashes with IDs reserved for other purposes (e.g. by
+    /// a parser). Note that if a rule IDs map is also set, it overrides the IDs of all named
+    /// rules (see [`set_rule_ids`](struct.LexerDef.html#method.set_rule_ids)), so `id_base` then
+    /// only affects unnamed rules (whose IDs are never exposed in lexemes).
+    pub fn id_base(mut self, id_base: usize) -> Self {
+        self.id_base = id_base;
+        self
+    }
+
+    /// If `true`, each named rule's ID is derived from its name (as the low bits of the 64-bit
+    /// FNV-1a hash of the name, truncated to the width of `TokId`) rather than from its position
+    /// in the `.l` file, so that adding, removing, or reordering rules does not change the IDs of
+    /// other rules (e.g. in serialised token streams). Unnamed rules are given no ID. An error
+    /// is returned if two rules' IDs collide: a wider `TokId` type makes collisions less likely.
+    /// If `TokId` is a signed integer type, its sign bit is not used, so that IDs are never
+    /// negative. `id_base` has no effect on hashed IDs, and
+    /// this option cannot be combined with a rule IDs map or runtime rule IDs. Defaults to
+    /// `false`.
+    pub fn hashed_ids(mut self, hashed_ids: bool) -> Self {
+        self.hashed_ids = hashed_ids;
+        self
+    }
+
+    /// If `true`, the generated `LexerDef`s' rules all have `None` IDs, and the generated module
+    /// additionally contains a function `set_ids(lexerdef, map)` which assigns rule IDs at
+    /// run-time (with the same semantics as
+    /// [`set_rule_ids`](struct.LexerDef.html#method.set_rule_ids)). This allows e.g. a plugin
+    /// host to control the numbering of rules. Since rule IDs are then not known statically,
+    /// this option cannot be combined with a rule IDs map. Defaults to `false`.
+    pub fn runtime_rule_ids(mut self, runtime_rule_ids: bool) -> Self {
+        self.runtime_rule_ids = runtime_rule_ids;
+        self
+    }
+
+    /// If `true`, the generated module additionally contains a trait `TokVisitor`, with a method
+    /// `visit_x(&mut self, lexeme: &Lexeme<TokId>)` for each named rule `x` (lower-cased), and a
+    /// function `dispatch(lexeme, visitor)` which calls the method corresponding to `lexeme`'s
+    /// ID. Since implementations of `TokVisitor` must define every method, this gives
+    /// compile-time checking that every kind of token is handled. Rule names must then be valid
+    /// (and, once lower-cased, distinct) Rust identifiers, and each rule must have the same
+    /// ID in every input file; this option cannot be combined with runtime rule IDs. Defaults to
+    /// `false`.
+    pub fn visitor(mut self, visitor: bool) -> Self {
+        self.visitor = visitor;
+        self
+    }
+
+    /// If `true`, the generated module additionally contains a function
+    /// `tok_name(id) -> Option<&'static str>` which returns the name of the rule with ID `id`
+    /// (or `None` if there is no such named rule). Since names are then static strings compiled
+    /// into the binary, `tok_name(lexeme.tok_id())` gives access to a lexeme's rule name without
+    /// any allocation or search of the `LexerDef`. The module also contains the inverse function
+    /// `tok_id(name: &str) -> Option<TokId>` (e.g. for tools which refer to tokens by name in
+    /// configuration or test files). Each ID must then have the same name in every input file;
+    /// this option cannot be combined with runtime rule IDs. Defaults to `false`.
+    pub fn tok_names(mut self, tok_names: bool) -> Self {
+        self.tok_names = tok_names;
+        self
+    }
+
+    /// If `true`, the generated module additionally contains a function
+    /// `lex(s: &str) -> Result<Vec<Lexeme<TokId>>, usize>` (or, for
+    /// [`process_files`](#method.process_files), `lex_x` for each input file `x.l`) which lexes
+    /// `s` as [`Lexer::lexemes`](struct.Lexer.html#method.lexemes) would, returning either the
+    /// lexemes or the byte offset of the first error. If every rule's regex is simple enough (a
+    /// sequence of literal characters and character classes such as `[a-z_]` or `[^"\n]`, each
+    /// optionally followed by `?`, `*`, or `+`, where a quantified item other than the last must
+    /// be followed by an item which matches different characters, as in `"[^"]*"` or
+    /// `[0-9]+\.[0-9]*`), `lex` is a hand-rolled scanner which does not use the regex engine at
+    /// all, giving a small, fast lexer for simple languages. Otherwise `lex` falls back to using
+    /// the generated `LexerDef`. This option cannot be combined with runtime rule IDs. Defaults to
+    /// `false`.
+    pub fn scanner(mut self, scanner: bool) -> Self {
+        self.scanner = scanner;
+        self
+    }
+
+    /// If `true`, the generated module additionally contains a constant
+    /// `RULE_REGEXES: &[&str]` (or, for [`process_files`](#method.process_files),
+    /// `RULE_REGEXES_X` for each input file `x.l`, upper-cased) listing the regex of each rule,
+    /// in rule order, exactly as written in the `.l` file (so that, for example,
+    /// `RULE_REGEXES[i]` is the regex of the rule at index `i` of the generated `LexerDef`). This
+    /// allows tools to display or reprocess a compiled lexer's rules without the `.l` file.
+    /// Defaults to `false`.
+    pub fn rule_regexes(mut self, rule_regexes: bool) -> Self {
+        self.rule_regexes = rule_regexes;
+        self
+    }
+
+    /// If `true`, return an error if any input file contains two rules which match exactly the
+    /// same strings (as detected by
+    /// [`LexerDef::equivalent_rules`](struct.LexerDef.html#method.equivalent_rules)), since the
+    /// later rule can then never match. Defaults to `false`.
+    pub fn check_equivalent_rules(mut self, check: bool) -> Self {
+        self.check_equivalent_rules = check;
+        self
+    }
+
+    /// Compile every rule's regex with the nesting limit `nest_limit` (see
+    /// `regex::RegexBuilder::nest_limit`), both when processing input files and in the generated
+    /// code. This is useful for machine-generated rules, whose regexes can nest more deeply than
+    /// the `regex` crate allows by default. A rule which exceeds the limit causes a
+    /// [`LexErrorKind::RegexNestLimit`](enum.LexErrorKind.html#variant.RegexNestLimit) error.
+    /// Defaults to the `regex` crate's own default.
+    pub fn nest_limit(mut self, nest_limit: u32) -> Self {
+        self.nest_limit = Some(nest_limit);
+        self
+    }
+
+    /// If `true`, the generated module additionally contains a function
+    /// `span_info(s, lexeme) -> (&str, usize, usize)` which returns the text of `lexeme` (which
+    /// must have been produced by lexing `s`) and its line and column numbers (as
+    /// [`Lexer::line_and_col`](struct.Lexer.html#method.line_and_col) would). Users of the
+    /// generated module can then report token locations without using lrlex's own APIs. Defaults
+    /// to `false`.
+    pub fn span_info(mut self, span_info: bool) -> Self {
+        self.span_info = span_info;
+        self
+    }
+
+    /// If `Some(name)`, the generated module additionally contains a type `name` wrapping the
+    /// generated [`LexerDef`](struct.LexerDef.html), which implements `Default` (creating the
+    /// `LexerDef`) and `Deref<Target = LexerDef<TokId>>`, so that e.g. `name::default().lexer(s)`
+    /// works as expected. This allows generated lexers to be stored in structs which derive
+    /// `Default`. This option cannot be used with
+    /// [`process_files`](#method.process_files). Defaults to `None`.
+    pub fn default_type(mut self, name: &str) -> Self {
+        self.default_type = Some(name.to_owned());
+        self
+    }
+
+    /// Precede the generated module with the attribute `#[cfg(<cfg>)]`, so that it is only
+    /// compiled if `cfg` holds. For example, `cfg("feature = \"grammar\"")` allows a crate to
+    /// make the generated lexer conditional on its `grammar` feature. `cfg` is copied verbatim into
+    /// the generated code, so it must be a valid `cfg` predicate. Defaults to no attribute.
+    pub fn cfg(mut self, cfg: &str) -> Self {
+        self.cfg = Some(cfg.to_owned());
+        self
+    }
+
+    /// As [`process_file_in_src`](fn.process_file_in_src.html), but using this builder's
+    /// settings.
+    pub fn process_file_in_src(&self, srcp: &str)
+                            -> Result<MissingNames, Box<dyn Error>>
+    {
+        let inp = src_path(srcp)?;
+        let mut leaf = inp.file_stem().unwrap().to_str().unwrap().to_owned();
+        leaf.push_str(LEX_SUFFIX);
+        self.process_file(&inp, out_dir_path(&leaf))
+    }
+
+    /// As [`process_file`](fn.process_file.html), but using this builder's settings.
+    pub fn process_file<P, Q>(&self, inp: P, outp: Q)
+                           -> Result<MissingNames, Box<dyn Error>>
+                        where P: AsRef<Path>,
+                              Q: AsRef<Path>
+    {
+        self.check_constant_names()?;
+        let (lexerdef, missing) = self.build_lexerdef(&inp)?;
+
+        let mut outs = String::new();
+        let mod_name = inp.as_ref().file_stem().unwrap().to_str().unwrap();
+        // Header
+        self.rust_pp_cfg(&mut outs);
+        outs.push_str(&format!("mod {}_l {{", mod_name));
+        outs.push_str("use lrlex::{LexerDef, Rule};\n\n");
+        lexerdef.rust_pp(&mut outs, LEX_FN_NAME, self.nest_limit);
+        if self.scanner {
+            lexerdef.rust_pp_scanner(&mut outs, SCANNER_FN_NAME, LEX_FN_NAME);
+        }
+        self.rust_pp_rule_regexes(&mut outs, &lexerdef, RULE_REGEXES_NAME);
+
+        // Token IDs
+        self.rust_pp_tok_ids(&mut outs, &[&lexerdef]);
+        self.rust_pp_set_ids(&mut outs);
+        self.rust_pp_visitor(&mut outs, &[&lexerdef])?;
+        self.rust_pp_tok_names(&mut outs, &[&lexerdef])?;
+        self.rust_pp_span_info(&mut outs);
+        self.rust_pp_default_type(&mut outs);
+
+        // Footer
+        outs.push('}');
+
+        write_if_changed(outp, outs.as_bytes())?;
+        Ok(missing)
+    }
+
+    /// Parse the `.l` file `inp` using this builder's settings and write the resulting
+    /// [`LexerDef`](struct.LexerDef.html) to `outp` in lrlex's binary cache format, so that it
+    /// can be loaded at run-time with
+    /// [`LexerDef::from_cache`](struct.LexerDef.html#method.from_cache) without reparsing `inp`.
+    /// The returned tuple is as for [`process_file`](fn.process_file.html). Only available with
+    /// the `cache` feature.
+    #[cfg(feature = "cache")]
+    pub fn process_file_to_cache<P, Q>(&self, inp: P, outp: Q)
+                                    -> Result<MissingNames, Box<dyn Error>>
+                                 where P: AsRef<Path>,
+                                       Q: AsRef<Path>,
+                                       usize: TryFrom<TokId>
+    {
+        let (lexerdef, missing) = self.build_lexerdef(&inp)?;
+        write_if_changed(outp, &lexerdef.to_cache()?)?;
+        Ok(missing)
+    }
+
+    /// As [`process_files`](#method.process_files), but with the input files in the project's
+    /// `src/` directory (in the same manner as
+    /// [`process_file_in_src`](fn.process_file_in_src.html)) and the output written to the
+    /// module `mod_name` in `OUT_DIR`, which can then be imported using `lrlex_mod!(mod_name)`.
+    pub fn process_files_in_src(&self, srcps: &[&str], mod_name: &str)
+                             -> Result<MissingNames, Box<dyn Error>>
+    {
+        let mut inps = Vec::with_capacity(srcps.len());
+        for srcp in srcps {
+            inps.push(src_path(srcp)?);
+        }
+        self.process_files(&inps, out_dir_path(mod_name))
+    }
+
+    /// Statically compile several `.l` files `inps` into a single Rust module, placing the
+    /// output into `outp`. The module is named after the leaf name of `outp` (without its
+    /// extension) and, for each input file `x.l`, defines a function `lexerdef_x()` which
+    /// returns the corresponding [`LexerDef`](struct.LexerDef.html). Since function names are
+    /// derived from the inputs' leaf names, these must be unique: an error is returned
+    /// otherwise.
+    ///
+    /// If a rule IDs map has been set, it is applied to every input, and the returned tuple
+    /// contains (*referenced_in_map_missing_from_all_lexers*,
+    /// *defined_in_any_lexer_missing_from_map*).
+    pub fn process_files<P, Q>(&self, inps: &[P], outp: Q)
+                            -> Result<MissingNames, Box<dyn Error>>
+                         where P: AsRef<Path>,
+                               Q: AsRef<Path>
+    {
+        self.check_constant_names()?;
+        if self.default_type.is_some() {
+            return Err("A default type cannot be generated for multiple input files".into());
+        }
+        let mut outs = String::new();
+        let mod_name = outp.as_ref().file_stem().unwrap().to_str().unwrap();
+        // Header
+        self.rust_pp_cfg(&mut outs);
+        outs.push_str(&format!("mod {} {{", mod_name));
+        outs.push_str("use lrlex::{LexerDef, Rule};\n\n");
+
+        let mut fn_names = HashSet::new();
+        let mut lexerdefs = Vec::with_capacity(inps.len());
+        let mut missing_from_lexer: Option<HashSet<String>> = None;
+        let mut missing_from_parser: Option<HashSet<String>> = None;
+        for (i, inp) in inps.iter().enumerate() {
+            let fn_name = format!("{}_{}",
+                                  LEX_FN_NAME,
+                                  inp.as_ref().file_stem().unwrap().to_str().unwrap());
+            if !fn_names.insert(fn_name.clone()) {
+                return Err(format!("Input file '{}' has the same leaf name as a previous input",
+                                   inp.as_ref().display()).into());
+            }
+            let (lexerdef, (mfl, mfp)) = self.build_lexerdef(inp)?;
+            lexerdef.rust_pp(&mut outs, &fn_name, self.nest_limit);
+            if self.scanner {
+                let scanner_fn_name = format!("{}_{}",
+                                              SCANNER_FN_NAME,
+                                              inp.as_ref()
+                                                 .file_stem()
+                                                 .unwrap()
+                                                 .to_str()
+                                                 .unwrap());
+                lexerdef.rust_pp_scanner(&mut outs, &scanner_fn_name, &fn_name);
+            }
+            let regexes_name = format!("{}_{}",
+                                       RULE_REGEXES_NAME,
+                                       inp.as_ref()
+                                          .file_stem()
+                                          .unwrap()
+                                          .to_str()
+                                          .unwrap()
+                                          .to_ascii_uppercase());
+            self.rust_pp_rule_regexes(&mut outs, &lexerdef, &regexes_name);
+            // A rule is only missing from the lexers if it's missing from all of them.
+            missing_from_lexer = match (i, missing_from_lexer, mfl) {
+                (0, _, mfl) => mfl,
+                (_, Some(x), Some(y)) => {
+                    let z = x.intersection(&y).cloned().collect::<HashSet<_>>();
+                    if z.is_empty() { None } else { Some(z) }
+                },
+                _ => None
+            };
+            if let Some(mfp) = mfp {
+                missing_from_parser.get_or_insert_with(HashSet::new).extend(mfp);
+            }
+            lexerdefs.push(lexerdef);
+        }
+
+        // Token IDs
+        self.rust_pp_tok_ids(&mut outs, &lexerdefs.iter().collect::<Vec<_>>());
+        self.rust_pp_set_ids(&mut outs);
+        self.rust_pp_visitor(&mut outs, &lexerdefs.iter().collect::<Vec<_>>())?;
+        self.rust_pp_tok_names(&mut outs, &lexerdefs.iter().collect::<Vec<_>>())?;
+        self.rust_pp_span_info(&mut outs);
+
+        // Footer
+        outs.push('}');
+
+        write_if_changed(outp, outs.as_bytes())?;
+        Ok((missing_from_lexer, missing_from_parser))
+    }
+
+    /// Parse the `.l` file `inp`, applying the rule IDs map (if any) to it. Returns the
+    /// resulting `LexerDef` and the sets returned by
+    /// [`set_rule_ids`](struct.LexerDef.html#method.set_rule_ids).
+    fn build_lexerdef<P: AsRef<Path>>(&self, inp: P)
+                                   -> Result<(LexerDef<TokId>, MissingNames), Box<dyn Error>>
+    {
+        if self.runtime_rule_ids && self.rule_ids_map.is_some() {
+            return Err("A rule IDs map cannot be combined with runtime rule IDs".into());
+        }
+        if self.runtime_rule_ids && self.visitor {
+            return Err("A visitor cannot be combined with runtime rule IDs".into());
+        }
+        if self.runtime_rule_ids && self.tok_names {
+            return Err("Token names cannot be combined with runtime rule IDs".into());
+        }
+        if self.runtime_rule_ids && self.scanner {
+            return Err("A scanner cannot be combined with runtime rule IDs".into());
+        }
+        if self.hashed_ids && (self.runtime_rule_ids || self.rule_ids_map.is_some()) {
+            return Err("Hashed rule IDs cannot be combined with a rule IDs map or runtime rule IDs"
+                       .into());
+        }
+        let inc = read_to_string(&inp).unwrap();
+        let mut lexerdef = parse_lex_with_nest_limit::<TokId>(&inc, self.id_base, self.nest_limit)?;
+        if self.check_equivalent_rules {
+            if let Some(&(i, j)) = lexerdef.equivalent_rules().first() {
+                let desc = |ridx| {
+                    let r = lexerdef.get_rule(ridx).unwrap();
+                    match r.name {
+                        Some(ref n) => format!("'{}'", n),
+                        None => format!("'{}' (unnamed)", r.re_str)
+                    }
+                };
+                return Err(format!("Rules {} and {} in '{}' match the same input",
+                                   desc(i),
+                                   desc(j),
+                                   inp.as_ref().display()).into());
+            }
+        }
+        if self.hashed_ids {
+            self.set_hashed_ids(&mut lexerdef)?;
+        }
+        let missing = match self.rule_ids_map {
+            Some(ref rim) => {
+                // Convert from HashMap<String, _> to HashMap<&str, _>
+                let owned_map = rim.iter()
+                                   .map(|(x, y)| (&**x, *y))
+                                   .collect::<HashMap<_, _>>();
+                let (x, y) = lexerdef.set_rule_ids(&owned_map);
+                (x.map(|a| a.iter()
+                            .map(|b| b.to_string())
+                            .collect::<HashSet<_>>()),
+                 y.map(|a| a.iter()
+                            .map(|b| b.to_string())
+                            .collect::<HashSet<_>>()))
+            },
+            None => (None, None)
+        };
+        if self.runtime_rule_ids {
+            for r in &mut lexerdef.rules {
+                r.tok_id = None;
+            }
+        }
+        Ok((lexerdef, missing))
+    }
+
+    /// Give each named rule in `lexerdef` an ID derived from a hash of its name, and each unnamed
+    /// rule no ID (see [`hashed_ids`](#method.hashed_ids)).
+    fn set_hashed_ids(&self, lexerdef: &mut LexerDef<TokId>) -> Result<(), Box<dyn Error>> {
+        let mut bits = size_of::<TokId>().min(size_of::<usize>()) * 8;
+        // If `TokId` can't represent a value with only its top bit set, it must be signed.
+        if TokId::try_from(1 << (bits - 1)).is_err() {
+            bits -= 1;
+        }
+        let mask = if bits >= 64 { u64::MAX } else { (1 << bits) - 1 };
+        let mut ids: Vec<(usize, &str)> = Vec::new();
+        for r in &mut lexerdef.rules {
+            let n = match r.name {
+                Some(ref n) => n,
+                None => {
+                    r.tok_id = None;
+                    continue;
+                }
+            };
+            let mut h = FnvHasher::new();
+            h.write(n.as_bytes());
+            let id = (h.finish() & mask) as usize;
+            if let Some(&(_, x_n)) = ids.iter().find(|&&(x_id, _)| x_id == id) {
+                return Err(format!("Rules '{}' and '{}' have the same hashed ID {}", x_n, n, id)
+                           .into());
+            }
+            r.tok_id = Some(TokId::try_from(id)
+                                  .map_err(|_| format!("Hashed ID {} does not fit in {}",
+                                                       id,
+                                                       TokId::type_name()))?);
+            ids.push((id, n));
+        }
+        Ok(())
+    }
+
+    /// Check that no two names in the rule IDs map (if any) generate the same `T_` constant
+    /// name, returning a `ConstantNameCollision` error otherwise.
+    fn check_constant_names(&self) -> Result<(), LexBuildError> {
+        if let Some(ref rim) = self.rule_ids_map {
+            let mut consts: HashMap<String, Vec<String>> = HashMap::new();
+            for n in rim.keys() {
+                consts.entry(n.to_ascii_uppercase()).or_default().push(n.clone());
+            }
+            let mut collisions = consts.into_values()
+                                       .filter(|names| names.len() > 1)
+                                       .collect::<Vec<_>>();
+            if !collisions.is_empty() {
+                for names in &mut collisions {
+                    names.sort();
+                }
+                collisions.sort();
+                return Err(LexBuildError{kind: LexErrorKind::ConstantNameCollision(
+                                                   collisions.swap_remove(0)),
+                                         line: 0,
+                                         col: 0});
+            }
+        }
+        Ok(())
+    }
+
+    /// If runtime rule IDs are enabled, pretty print the `set_ids` function into `outs`.
+    fn rust_pp_set_ids(&self, outs: &mut String) {
+        if self.runtime_rule_ids {
+            outs.push_str(&format!("#[allow(dead_code)]
+pub fn set_ids<'a>(lexerdef: &'a mut LexerDef<{tokid}>,
+                   map: &::std::collections::HashMap<&'a str, {tokid}>)
+                -> (Option<::std::collections::HashSet<&'a str>>,
+                    Option<::std::collections::HashSet<&'a str>>)
+{{
+    lexerdef.set_rule_ids(map)
+}}
+", tokid=TokId::type_name()));
+        }
+    }
+
+    /// If a `cfg` predicate is set, pretty print the attribute which precedes the generated
+    /// module into `outs`.
+    fn rust_pp_cfg(&self, outs: &mut String) {
+        if let Some(ref cfg) = self.cfg {
+            outs.push_str(&format!("#[cfg({})]\n", cfg));
+        }
+    }
+
+    /// If visitor generation is enabled, pretty print the `TokVisitor` trait and `dispatch`
+    /// function for the named rules in `lexerdefs` into `outs`.
+    fn rust_pp_visitor(&self, outs: &mut String, lexerdefs: &[&LexerDef<TokId>])
+                    -> Result<(), Box<dyn Error>>
+    {
+        if !self.visitor {
+            return Ok(());
+        }
+        // The (method name, ID) of each named rule, in the order rules are first defined.
+        let mut visits: Vec<(String, TokId)> = Vec::new();
+        for r in lexerdefs.iter().flat_map(|ld| ld.iter_rules()) {
+            let (n, id) = match (r.name.as_ref(), r.tok_id) {
+                (Some(n), Some(id)) => (n, id),
+                _ => continue
+            };
+            if n.is_empty() || !n.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("Rule name '{}' is not a valid Rust identifier", n).into());
+            }
+            let mn = format!("visit_{}", n.to_ascii_lowercase());
+            match visits.iter().find(|(x, _)| *x == mn) {
+                Some(&(_, x_id)) if x_id == id && lexerdefs.len() > 1 => continue,
+                Some(_) => {
+                    return Err(format!("Rule name '{}' clashes with another rule's visitor method",
+                                       n).into());
+                },
+                None => visits.push((mn, id))
+            }
+        }
+
+        outs.push_str("use lrlex::Lexeme;\n\n#[allow(dead_code)]\npub trait TokVisitor {\n");
+        for (mn, _) in &visits {
+            outs.push_str(&format!("    fn {}(&mut self, lexeme: &Lexeme<{}>);\n",
+                                   mn,
+                                   TokId::type_name()));
+        }
+        outs.push_str(&format!("}}
+
+#[allow(dead_code)]
+pub fn dispatch<V: TokVisitor>(lexeme: &Lexeme<{}>, visitor: &mut V) {{
+    match lexeme.tok_id() {{
+", TokId::type_name()));
+        for &(ref mn, id) in &visits {
+            outs.push_str(&format!("        {:?} => visitor.{}(lexeme),\n", id, mn));
+        }
+        outs.push_str("        _ => unreachable!()\n    }\n}\n");
+        Ok(())
+    }
+
+    /// If token names are enabled, pretty print the `tok_name` function for the named rules in
+    /// `lexerdefs` into `outs`.
+    fn rust_pp_tok_names(&self, outs: &mut String, lexerdefs: &[&LexerDef<TokId>])
+                      -> Result<(), Box<dyn Error>>
+    {
+        if !self.tok_names {
+            return Ok(());
+        }
+        let mut names: Vec<(TokId, &str)> = Vec::new();
+        for r in lexerdefs.iter().flat_map(|ld| ld.iter_rules()) {
+            let (id, n) = match (r.tok_id, r.name.as_ref()) {
+                (Some(id), Some(n)) => (id, n.as_str()),
+                _ => continue
+            };
+            match names.iter().find(|&&(x_id, _)| x_id == id) {
+                Some(&(_, x_n)) if x_n == n => (),
+                Some(&(_, x_n)) => {
+                    return Err(format!("Rules '{}' and '{}' have the same ID", x_n, n).into());
+                },
+                None => names.push((id, n))
+            }
+        }
+
+        outs.push_str(&format!("#[allow(dead_code)]
+pub fn tok_name(id: {}) -> Option<&'static str> {{
+    match id {{
+", TokId::type_name()));
+        for &(id, n) in &names {
+            outs.push_str(&format!("        {:?} => Some({:?}),\n", id, n));
+        }
+        outs.push_str("        _ => None\n    }\n}\n");
+        outs.push_str(&format!("#[allow(dead_code)]
+pub fn tok_id(name: &str) -> Option<{}> {{
+    match name {{
+", TokId::type_name()));
+        for &(id, n) in &names {
+            outs.push_str(&format!("        {:?} => Some({:?}),\n", n, id));
+        }
+        outs.push_str("        _ => None\n    }\n}\n");
+        Ok(())
+    }
+
+    /// If span information is enabled, pretty print the `span_info` function into `outs`.
+    fn rust_pp_span_info(&self, outs: &mut String) {
+        if !self.span_info {
+            return;
+        }
+        outs.push_str(&format!("#[allow(dead_code)]
+pub fn span_info<'a>(s: &'a str, lexeme: &::lrlex::Lexeme<{}>) -> (&'a str, usize, usize) {{
+    let before = &s[..lexeme.start()];
+    let line_start = before.rfind('\\n').map_or(0, |i| i + 1);
+    (&s[lexeme.start()..lexeme.start() + lexeme.len()],
+     before.matches('\\n').count() + 1,
+     lexeme.start() - line_start + 1)
+}}
+", TokId::type_name()));
+    }
+
+    /// If the rule regexes table is enabled, pretty print it for `lexerdef` as the constant
+    /// `const_name` into `outs`.
+    fn rust_pp_rule_regexes(&self,
+                            outs: &mut String,
+                            lexerdef: &LexerDef<TokId>,
+                            const_name: &str)
+    {
+        if !self.rule_regexes {
+            return;
+        }
+        outs.push_str(&format!("#[allow(dead_code)]\npub const {}: &[&str] = &[\n", const_name));
+        for r in lexerdef.iter_rules() {
+            outs.push_str(&format!("    {:?},\n", r.re_str));
+        }
+        outs.push_str("];\n");
+    }
+
+    /// If a default type has been set, pretty print it and its `Default` and `Deref` impls into
+    /// `outs`.
+    fn rust_pp_default_type(&self, outs: &mut String) {
+        if let Some(ref name) = self.default_type {
+            outs.push_str(&format!("#[allow(dead_code)]
+pub struct {name}(pub LexerDef<{tokid}>);
+
+impl Default for {name} {{
+    fn default() -> Self {{
+        {name}({lex_fn}())
+    }}
+}}
+
+impl ::std::ops::Deref for {name} {{
+    type Target = LexerDef<{tokid}>;
+
+    fn deref(&self) -> &LexerDef<{tokid}> {{
+        &self.0
+    }}
+}}
+", name = name, tokid = TokId::type_name(), lex_fn = LEX_FN_NAME));
+        }
+    }
+
+    /// Pretty print the token ID constants from the rule IDs map (if any) into `outs`. If a rule
+    /// in `lexerdefs` has a `%doc` description, it is emitted as the constant's doc comment.
+    fn rust_pp_tok_ids(&self, outs: &mut String, lexerdefs: &[&LexerDef<TokId>]) {
+        if let Some(ref rim) = self.rule_ids_map {
+            for (n, id) in rim {
+                let doc = lexerdefs.iter()
+                                   .filter_map(|ld| ld.get_rule_by_name(n))
+                                   .filter_map(|r| r.doc.as_ref())
+                                   .next();
+                if let Some(doc) = doc {
+                    outs.push_str(&format!("/// {}\n", doc));
+                }
+                outs.push_str(&format!("#[allow(dead_code)]\nconst T_{}: {} = {:?};\n",
+                                       n.to_ascii_uppercase(),
+                                       TokId::type_name(),
+                                       *id));
+            }
+        }
+    }
+}
+
+impl<TokId> Default for LexerBuilder<TokId>
+    where TokId: Copy + Debug + Eq + TryFrom<usize> + TypeName
+{
+    fn default() -> Self {
+        LexerBuilder::new()
+    }
+}
+
 /// Given the filename `x.l` as input, it will statically compile the file `src/x.l` into a Rust
 /// module which can then be imported using `lrlex_mod!(x_l)`. This is a convenience function
 /// around [`process_file`](fn.process_file.html) which makes it easier to compile `.l` files
@@ -57,30 +746,16 @@ const RUST_FILE_EXT: &str = "rs";
 /// contain).
 ///
 /// See [`process_file`](fn.process_file.html)'s documentation for information about the
-/// `rule_ids_map` argument and the returned tuple.
-///
-/// # Panics
-///
-/// If the input filename does not end in `.l`.
+/// `rule_ids_map` argument and the returned tuple. If the input filename does not end in `.l`, a
+/// [`LexBuildError`](struct.LexBuildError.html) of kind `BadInputPath` is returned.
 pub fn process_file_in_src<TokId>(srcp: &str,
                                   rule_ids_map: Option<HashMap<String, TokId>>)
-                               -> Result<(Option<HashSet<String>>, Option<HashSet<String>>),
-                                         Box<Error>>
+                               -> Result<MissingNames, Box<dyn Error>>
                             where TokId: Copy + Debug + Eq + TryFrom<usize> + TypeName
 {
-    let mut inp = current_dir()?;
-    inp.push("src");
-    inp.push(srcp);
-    if Path::new(srcp).extension().unwrap().to_str().unwrap() != LEX_FILE_EXT {
-        panic!("File name passed to process_file_in_src must have extension '{}'.", LEX_FILE_EXT);
-    }
-    let mut leaf = inp.file_stem().unwrap().to_str().unwrap().to_owned();
-    leaf.push_str(&LEX_SUFFIX);
-    let mut outp = PathBuf::new();
-    outp.push(var("OUT_DIR").unwrap());
-    outp.push(leaf);
-    outp.set_extension(RUST_FILE_EXT);
-    process_file::<TokId, _, _>(inp, outp, rule_ids_map)
+    let mut lb = LexerBuilder::new();
+    lb.rule_ids_map = rule_ids_map;
+    lb.process_file_in_src(srcp)
 }
 
 /// Statically compile the `.l` file `inp` into Rust, placing the output into `outp`. The latter
@@ -95,94 +770,407 @@ pub fn process_file_in_src<TokId>(srcp: &str,
 pub fn process_file<TokId, P, Q>(inp: P,
                                  outp: Q,
                                  rule_ids_map: Option<HashMap<String, TokId>>)
-                              -> Result<(Option<HashSet<String>>, Option<HashSet<String>>),
-                                        Box<Error>>
+                              -> Result<MissingNames, Box<dyn Error>>
                            where TokId: Copy + Debug + Eq + TryFrom<usize> + TypeName,
                                  P: AsRef<Path>,
                                  Q: AsRef<Path>
 {
-    let inc = read_to_string(&inp).unwrap();
-    let mut lexerdef = parse_lex::<TokId>(&inc)?;
-    let (missing_from_lexer, missing_from_parser) = match rule_ids_map {
-        Some(ref rim) => {
-            // Convert from HashMap<String, _> to HashMap<&str, _>
-            let owned_map = rim.iter()
-                               .map(|(x, y)| (&**x, *y))
-                               .collect::<HashMap<_, _>>();
-            match lexerdef.set_rule_ids(&owned_map) {
-                (x, y) => {
-                    (x.map(|a| a.iter()
-                                .map(|b| b.to_string())
-                                .collect::<HashSet<_>>()),
-                     y.map(|a| a.iter()
-                                .map(|b| b.to_string())
-                                .collect::<HashSet<_>>()))
-                }
-            }
-        },
-        None => (None, None)
-    };
-
-    let mut outs = String::new();
-    let mod_name = inp.as_ref().file_stem().unwrap().to_str().unwrap();
-    // Header
-    outs.push_str(&format!("mod {}_l {{", mod_name));
-    lexerdef.rust_pp(&mut outs);
+    let mut lb = LexerBuilder::new();
+    lb.rule_ids_map = rule_ids_map;
+    lb.process_file(inp, outp)
+}
 
-    // Token IDs
-    if let Some(rim) = rule_ids_map {
-        for (n, id) in &rim {
-            outs.push_str(&format!("#[allow(dead_code)]\nconst T_{}: {} = {:?};\n",
-                                   n.to_ascii_uppercase(),
-                                   TokId::type_name(),
-                                   *id));
-        }
+/// Return the path of the `.l` file `srcp` in the current project's `src/` directory, or an error
+/// if `srcp` does not have a `.l` extension.
+fn src_path(srcp: &str) -> Result<PathBuf, Box<dyn Error>> {
+    if Path::new(srcp).extension().and_then(|e| e.to_str()) != Some(LEX_FILE_EXT) {
+        return Err(Box::new(LexBuildError{kind: LexErrorKind::BadInputPath(PathBuf::from(srcp)),
+                                          line: 0,
+                                          col: 0}));
     }
+    let mut inp = current_dir()?;
+    inp.push("src");
+    inp.push(srcp);
+    Ok(inp)
+}
 
-    // Footer
-    outs.push_str("}");
+/// Return the path of the Rust file for the module `leaf` in `OUT_DIR`.
+fn out_dir_path(leaf: &str) -> PathBuf {
+    let mut outp = PathBuf::new();
+    outp.push(var("OUT_DIR").unwrap());
+    outp.push(leaf);
+    outp.set_extension(RUST_FILE_EXT);
+    outp
+}
 
-    // If the file we're about to write out already exists with the same contents, then we don't
-    // overwrite it (since that will force a recompile of the file, and relinking of the binary
-    // etc).
-    if let Ok(curs) = read_to_string(&outp) {
+/// Write `outs` to `outp`. If the file we're about to write out already exists with the same
+/// contents, then we don't overwrite it (since that will force a recompile of the file, and
+/// relinking of the binary etc).
+fn write_if_changed<P: AsRef<Path>>(outp: P, outs: &[u8]) -> Result<(), Box<dyn Error>> {
+    if let Ok(curs) = read(&outp) {
         if curs == outs {
-            return Ok((missing_from_lexer, missing_from_parser));
+            return Ok(());
         }
     }
     let mut f = File::create(outp)?;
-    f.write_all(outs.as_bytes())?;
-    Ok((missing_from_lexer, missing_from_parser))
+    f.write_all(outs)?;
+    Ok(())
 }
 
 impl<TokId: Copy + Debug + Eq + TypeName> LexerDef<TokId> {
-    pub(crate) fn rust_pp(&self, outs: &mut String) {
+    /// Pretty print this `LexerDef` as a Rust function `fn_name` which returns the `LexerDef`,
+    /// compiling rules' regexes with `nest_limit` if it is `Some`. The caller is responsible for
+    /// importing `LexerDef` and `Rule`.
+    pub(crate) fn rust_pp(&self, outs: &mut String, fn_name: &str, nest_limit: Option<u32>) {
         // Header
-        outs.push_str(&format!("use lrlex::{{LexerDef, Rule}};
-
-pub fn lexerdef() -> LexerDef<{}> {{
-    let rules = vec![", TokId::type_name()));
-
-        // Individual rules
+        let has_directives = self.rules.iter().any(|r| r.category.is_some() || r.error.is_some());
+        outs.push_str(&format!("pub fn {}() -> LexerDef<{}> {{
+    let {}rules = vec![", fn_name, TokId::type_name(), if has_directives { "mut " } else { "" }));
+        // Individual rules, each followed by a comment giving its name and ID
         for r in &self.rules {
-            let tok_id = match r.tok_id {
-                Some(ref t) => format!("Some({:?})", t),
-                None => "None".to_owned()
+            let (tok_id, id_comment) = match r.tok_id {
+                Some(ref t) => (format!("Some({:?})", t), format!("{:?}", t)),
+                None => ("None".to_owned(), "None".to_owned())
             };
-            let n = match r.name {
-                Some(ref n) => format!("Some({:?}.to_string())", n),
-                None => "None".to_owned()
+            let (n, n_comment) = match r.name {
+                Some(ref n) => (format!("Some({:?}.to_string())", n), format!("'{}'", n)),
+                None => ("None".to_owned(), "(unnamed)".to_owned())
+            };
+            let re_str = r.re_str.replace("\\", "\\\\").replace("\"", "\\\"");
+            let new = match nest_limit {
+                Some(l) => {
+                    format!("Rule::new_with_nest_limit({}, {}, \"{}\".to_string(), Some({}))",
+                            tok_id, n, re_str, l)
+                },
+                None => format!("Rule::new({}, {}, \"{}\".to_string())", tok_id, n, re_str)
             };
             outs.push_str(&format!("
-Rule::new({}, {}, \"{}\".to_string()).unwrap(),",
-                tok_id, n, r.re_str.replace("\\", "\\\\").replace("\"", "\\\"")));
+{}.unwrap(), // {} = {}", new, n_comment, id_comment));
+        }
+
+        outs.push_str("
+];");
+        // Categories and error messages (from `%category` and `%error` directives)
+        for (i, r) in self.rules.iter().enumerate() {
+            if let Some(ref c) = r.category {
+                outs.push_str(&format!("
+    rules[{}].category = Some({:?}.to_string());", i, c));
+            }
+            if let Some(ref e) = r.error {
+                outs.push_str(&format!("
+    rules[{}].error = Some({:?}.to_string());", i, e));
+            }
         }
 
         // Footer
         outs.push_str("
-];
     LexerDef::new(rules)
 }
 ");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    use super::*;
+
+    /// Write `src` to the file `a.l` in a fresh temporary directory `lrlex_test_<name>`, call `f`
+    /// with the paths of that file and of `a_l.rs` in the same directory, and then remove the
+    /// directory.
+    fn with_l_file<F: FnOnce(&Path, &Path)>(name: &str, src: &[u8], f: F) {
+        let mut dir = temp_dir();
+        dir.push(format!("lrlex_test_{}", name));
+        create_dir_all(&dir).unwrap();
+        let inp = dir.join("a.l");
+        File::create(&inp).unwrap().write_all(src).unwrap();
+        f(&inp, &dir.join("a_l.rs"));
+        remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_process_files() {
+        with_l_file("process_files", b"%%\n[a-z]+ 'ID'\n[ ] ;", |inp, _| {
+            let inp_b = inp.with_file_name("b.l");
+            File::create(&inp_b).unwrap()
+                                .write_all(b"%%\n%doc 'An integer'\n[0-9]+ 'INT'")
+                                .unwrap();
+            let mut inps = vec![inp.to_path_buf(), inp_b];
+            let outp = inp.with_file_name("lexers.rs");
+
+            let mut rim = HashMap::new();
+            rim.insert("ID".to_string(), 0u8);
+            rim.insert("INT".to_string(), 1u8);
+            rim.insert("FLOAT".to_string(), 2u8);
+            let mut missing_from_lexer = HashSet::new();
+            missing_from_lexer.insert("FLOAT".to_string());
+            assert_eq!(LexerBuilder::new().rule_ids_map(rim).process_files(&inps, &outp).unwrap(),
+                       (Some(missing_from_lexer), None));
+            let outs = read_to_string(&outp).unwrap();
+            assert!(outs.starts_with("mod lexers {"));
+            assert!(outs.contains("pub fn lexerdef_a() -> LexerDef<u8>"));
+            assert!(outs.contains("pub fn lexerdef_b() -> LexerDef<u8>"));
+            assert!(outs.contains("/// An integer\n#[allow(dead_code)]\nconst T_INT: u8 = 1;"));
+
+            inps.push(inps[0].clone());
+            assert!(LexerBuilder::<u8>::new().process_files(&inps, &outp).is_err());
+        });
+    }
+
+    #[test]
+    fn test_bad_input_path() {
+        for p in &["foo", "foo.y"] {
+            let e = LexerBuilder::<u8>::new().process_file_in_src(p).unwrap_err();
+            match e.downcast_ref::<LexBuildError>() {
+                Some(LexBuildError{kind: LexErrorKind::BadInputPath(ref bp), ..}) => {
+                    assert_eq!(bp, &PathBuf::from(p));
+                    assert_eq!(e.to_string(),
+                               format!("Input file '{}' must have the extension '.l'", p));
+                },
+                _ => panic!("Incorrect error returned {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn test_runtime_rule_ids() {
+        with_l_file("runtime_rule_ids", b"%%\n[a-z]+ 'ID'\n[ ] ;", |inp, outp| {
+            LexerBuilder::<u8>::new().runtime_rule_ids(true).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains("Rule::new(None, Some(\"ID\".to_string())"));
+            assert!(outs.contains("pub fn set_ids<'a>(lexerdef: &'a mut LexerDef<u8>,"));
+
+            let mut rim = HashMap::new();
+            rim.insert("ID".to_string(), 0u8);
+            assert!(LexerBuilder::new().rule_ids_map(rim)
+                                       .runtime_rule_ids(true)
+                                       .process_file(inp, outp)
+                                       .is_err());
+        });
+    }
+
+    #[test]
+    fn test_rule_id_comments() {
+        with_l_file("rule_id_comments", b"%%\n[a-z]+ 'ID'\n[ ] ;", |inp, outp| {
+            LexerBuilder::<u8>::new().id_base(3).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains(".unwrap(), // 'ID' = 3\n"));
+            assert!(outs.contains(".unwrap(), // (unnamed) = 4\n"));
+        });
+    }
+
+    #[test]
+    fn test_nest_limit() {
+        let src = format!("%%\n{}a{} 'A'", "(".repeat(300), ")".repeat(300));
+        with_l_file("nest_limit", src.as_bytes(), |inp, outp| {
+            assert!(LexerBuilder::<u8>::new().process_file(inp, outp).is_err());
+            LexerBuilder::<u8>::new().nest_limit(1000).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains(concat!("Rule::new_with_nest_limit(Some(0), ",
+                                          "Some(\"A\".to_string()), \"((")));
+            assert!(outs.contains("\".to_string(), Some(1000)).unwrap(), // 'A' = 0\n"));
+        });
+    }
+
+    #[test]
+    fn test_categories() {
+        let src = b"%%\n[a-z]+ 'ID'\n%category 'comment'\n#.* ;\n%error 'bad'\n[0-9]+ ;";
+        with_l_file("categories", src, |inp, outp| {
+            LexerBuilder::<u8>::new().process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains("let mut rules = vec!["));
+            assert!(outs.contains(concat!("\n];\n    rules[1].category = ",
+                                          "Some(\"comment\".to_string());\n")));
+            assert!(outs.contains("\n    rules[2].error = Some(\"bad\".to_string());\n"));
+        });
+    }
+
+    #[test]
+    fn test_cfg() {
+        with_l_file("cfg", b"%%\n[a-z]+ 'ID'\n[ ] ;", |inp, outp| {
+            LexerBuilder::<u8>::new().cfg("feature = \"grammar\"")
+                                     .process_file(inp, outp)
+                                     .unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.starts_with("#[cfg(feature = \"grammar\")]\nmod a_l {"));
+        });
+    }
+
+    #[cfg(feature = "cache")]
+    #[test]
+    fn test_process_file_to_cache() {
+        with_l_file("process_file_to_cache", b"%%\n[a-z]+ 'ID'\n[ ] ;", |inp, _| {
+            let outp = inp.with_file_name("a.lrlexc");
+            LexerBuilder::<u8>::new().process_file_to_cache(inp, &outp).unwrap();
+            let lexerdef = LexerDef::<u8>::from_cache(&read(&outp).unwrap()).unwrap();
+            assert_eq!(lexerdef.get_rule_by_name("ID").unwrap().tok_id, Some(0));
+        });
+    }
+
+    #[test]
+    fn test_constant_name_collision() {
+        with_l_file("constant_name_collision", b"%%\n[a-z]+ 'id'\n[A-Z]+ 'Id'\n[ ] ;", |inp, outp| {
+            let mut rim = HashMap::new();
+            rim.insert("id".to_string(), 0u8);
+            rim.insert("Id".to_string(), 1u8);
+            match LexerBuilder::new().rule_ids_map(rim).process_file(inp, outp) {
+                Err(e) => match e.downcast_ref::<LexBuildError>() {
+                    Some(LexBuildError{kind: LexErrorKind::ConstantNameCollision(ref names),
+                                       ..}) => {
+                        assert_eq!(names, &["Id".to_string(), "id".to_string()]);
+                    },
+                    _ => panic!("Incorrect error {}", e)
+                },
+                Ok(_) => panic!("Colliding constant names accepted")
+            }
+        });
+    }
+
+    #[test]
+    fn test_tok_names() {
+        with_l_file("tok_names", b"%%\n[0-9]+ 'INT'\n\\+ '+'\n[ ] ;", |inp, outp| {
+            LexerBuilder::<u8>::new().tok_names(true).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains("pub fn tok_name(id: u8) -> Option<&'static str> {\n"));
+            assert!(outs.contains(concat!("        0 => Some(\"INT\"),\n",
+                                          "        1 => Some(\"+\"),\n",
+                                          "        _ => None\n")));
+            assert!(outs.contains("pub fn tok_id(name: &str) -> Option<u8> {\n"));
+            assert!(outs.contains(concat!("        \"INT\" => Some(0),\n",
+                                          "        \"+\" => Some(1),\n",
+                                          "        _ => None\n")));
+        });
+    }
+
+    #[test]
+    fn test_span_info() {
+        with_l_file("span_info", b"%%\n[0-9]+ 'INT'\n[ ] ;", |inp, outp| {
+            LexerBuilder::<u8>::new().process_file(inp, outp).unwrap();
+            assert!(!read_to_string(outp).unwrap().contains("fn span_info"));
+            LexerBuilder::<u8>::new().span_info(true).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains(concat!("pub fn span_info<'a>(s: &'a str, ",
+                                          "lexeme: &::lrlex::Lexeme<u8>) ",
+                                          "-> (&'a str, usize, usize) {\n")));
+        });
+    }
+
+    #[test]
+    fn test_default_type() {
+        with_l_file("default_type", b"%%\n[0-9]+ 'INT'\n[ ] ;", |inp, outp| {
+            LexerBuilder::<u8>::new().default_type("ALexer").process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains("pub struct ALexer(pub LexerDef<u8>);\n"));
+            assert!(outs.contains("impl Default for ALexer {\n    fn default() -> Self {\n"));
+            assert!(outs.contains("        ALexer(lexerdef())\n"));
+            assert!(LexerBuilder::<u8>::new().default_type("ALexer")
+                                             .process_files(&[inp], outp)
+                                             .is_err());
+        });
+    }
+
+    #[test]
+    fn test_rule_regexes() {
+        with_l_file("rule_regexes", b"%%\n[0-9]+ 'INT'\n\"[^\"]*\" 'STR'\n[ ] ;", |inp, outp| {
+            LexerBuilder::<u8>::new().process_file(inp, outp).unwrap();
+            assert!(!read_to_string(outp).unwrap().contains("RULE_REGEXES"));
+            LexerBuilder::<u8>::new().rule_regexes(true).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains(concat!("pub const RULE_REGEXES: &[&str] = &[\n",
+                                          "    \"[0-9]+\",\n",
+                                          "    \"\\\"[^\\\"]*\\\"\",\n",
+                                          "    \"[ ]\",\n",
+                                          "];\n")));
+            LexerBuilder::<u8>::new().rule_regexes(true).process_files(&[inp], outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains("pub const RULE_REGEXES_A: &[&str] = &[\n"));
+        });
+    }
+
+    #[test]
+    fn test_hashed_ids() {
+        with_l_file("hashed_ids", b"%%\n[0-9]+ 'INT'\n[a-z]+ 'ID'\n[ ] ;", |inp, outp| {
+            LexerBuilder::<u32>::new().hashed_ids(true).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains("Rule::new(Some(3375446302), Some(\"INT\".to_string())"));
+            assert!(outs.contains("Rule::new(Some(3048789568), Some(\"ID\".to_string())"));
+            assert!(outs.contains("Rule::new(None, None"));
+
+            // Inserting a rule does not change the other rules' IDs.
+            File::create(inp).unwrap().write_all(b"%%\n\\+ 'PLUS'\n[0-9]+ 'INT'\n[ ] ;").unwrap();
+            LexerBuilder::<u32>::new().hashed_ids(true).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains("Rule::new(Some(3375446302), Some(\"INT\".to_string())"));
+
+            File::create(inp).unwrap().write_all(b"%%\na 'EX'\nb 'FA'\n").unwrap();
+            assert!(LexerBuilder::<u32>::new().hashed_ids(true).process_file(inp, outp).is_ok());
+            match LexerBuilder::<u8>::new().hashed_ids(true).process_file(inp, outp) {
+                Err(e) => assert_eq!(e.to_string(),
+                                     "Rules 'EX' and 'FA' have the same hashed ID 232"),
+                Ok(_) => panic!()
+            }
+            match LexerBuilder::<i8>::new().hashed_ids(true).process_file(inp, outp) {
+                Err(e) => assert_eq!(e.to_string(),
+                                     "Rules 'EX' and 'FA' have the same hashed ID 104"),
+                Ok(_) => panic!()
+            }
+            let mut rim = HashMap::new();
+            rim.insert("EX".to_string(), 0);
+            rim.insert("FA".to_string(), 1);
+            let lb = LexerBuilder::<u32>::new().hashed_ids(true).rule_ids_map(rim);
+            assert!(lb.process_file(inp, outp).is_err());
+        });
+    }
+
+    #[test]
+    fn test_scanner() {
+        with_l_file("scanner", b"%%\n[0-9]+ 'INT'\n\\+ '+'\n[ ] ;", |inp, outp| {
+            LexerBuilder::<u8>::new().scanner(true).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains(concat!("pub fn lex(s: &str) ",
+                                          "-> Result<Vec<::lrlex::Lexeme<u8>>, usize> {")));
+            assert!(!outs.contains("    #[inline]\n    fn scan_r0"));
+            assert!(outs.contains("    #[inline]\n    fn scan_r1(t: &str) -> usize {\n\
+                                   \x20       if t.starts_with(\"+\") { 1 } else { 0 }\n"));
+
+            File::create(inp).unwrap().write_all(b"%%\n[0-9]+ 'INT'\n%error 'bad'\n@ ;").unwrap();
+            LexerBuilder::<u8>::new().scanner(true).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains("            1 => return Err(i),\n"));
+
+            File::create(inp).unwrap().write_all(b"%%\n[0-9]+|x 'INT'\n").unwrap();
+            LexerBuilder::<u8>::new().scanner(true).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains("    lexerdef().lexer(s).lexemes().map_err(|e| e.idx())\n"));
+        });
+    }
+
+    #[test]
+    fn test_check_equivalent_rules() {
+        let src = b"%%\n[a-z]+ 'ID'\n[ ] ;\n[a-mn-z]+ 'ID2'";
+        with_l_file("check_equivalent_rules", src, |inp, outp| {
+            LexerBuilder::<u8>::new().process_file(inp, outp).unwrap();
+            match LexerBuilder::<u8>::new().check_equivalent_rules(true).process_file(inp, outp) {
+                Err(e) => assert!(e.to_string().starts_with("Rules 'ID' and 'ID2' in")),
+                Ok(_) => panic!("Equivalent rules accepted")
+            }
+        });
+    }
+
+    #[test]
+    fn test_visitor() {
+        with_l_file("visitor", b"%%\n[0-9]+ 'INT'\n[a-z]+ 'ID'\n[ ] ;", |inp, outp| {
+            LexerBuilder::<u8>::new().visitor(true).process_file(inp, outp).unwrap();
+            let outs = read_to_string(outp).unwrap();
+            assert!(outs.contains("    fn visit_int(&mut self, lexeme: &Lexeme<u8>);\n"));
+            assert!(outs.contains("    fn visit_id(&mut self, lexeme: &Lexeme<u8>);\n"));
+            assert!(outs.contains("        0 => visitor.visit_int(lexeme),\n"));
+            assert!(outs.contains("        1 => visitor.visit_id(lexeme),\n"));
+
+            File::create(inp).unwrap().write_all(b"%%\n\\+ '+'\n").unwrap();
+            assert!(LexerBuilder::<u8>::new().visitor(true).process_file(inp, outp).is_err());
+        });
+    }
+}
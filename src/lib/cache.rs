@@ -0,0 +1,287 @@
+// Copyright (c) 2017 King's College London
+// created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any person obtaining a
+// copy of this software, associated documentation and/or data (collectively the "Software"), free
+// of charge and under any and all copyright rights in the Software, and any and all patent rights
+// owned or freely licensable by each licensor hereunder covering either (i) the unmodified
+// Software as contributed to or provided by such licensor, or (ii) the Larger Works (as defined
+// below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software is contributed
+// by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create derivative works
+// of, display, perform, and distribute the Software and make, use, sell, offer for sale, import,
+// export, have made, and have sold the Software and the Larger Work(s), and to sublicense the
+// foregoing rights on either these or other terms.
+//
+// This license is subject to the following condition: The above copyright notice and either this
+// complete permission notice or at a minimum a reference to the UPL must be included in all copies
+// or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// A compact binary format for caching `LexerDef`s, so that applications which load lexers at
+// run-time need not parse `.l` files. The format is:
+//
+//   * the magic bytes `LRLX`;
+//   * the format version (`CACHE_VERSION`) as a LEB128 integer;
+//   * the number of rules as a LEB128 integer;
+//   * for each rule, in order: its ID, name, regex, regex nesting limit, whether its regex is
+//     multi-line (see `LexerDef::set_multi_line`), `%doc` description, `%category`, and `%error`
+//     message.
+//
+// Optional values are a 0 byte (`None`) or a 1 byte followed by the value; booleans are a 0 or 1
+// byte; integers are LEB128; strings are a LEB128 length followed by that many UTF-8 bytes. As
+// with `process_file`, properties which can only be set at run-time (e.g. continuation
+// predicates) are not stored, with the exception of regexes' multi-line modes, which are needed
+// to recompile them correctly. Regexes are recompiled, with their nesting limits and multi-line
+// modes, when a cache is loaded. This module is only compiled with the `cache` feature.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::str;
+
+use lexer::{LexerDef, Rule};
+
+const CACHE_MAGIC: &[u8] = b"LRLX";
+/// The version of the cache format. This must be incremented whenever the format changes, so that
+/// stale caches are rejected.
+const CACHE_VERSION: u64 = 5;
+
+impl<TokId: Copy + Eq> LexerDef<TokId>
+    where usize: TryFrom<TokId>
+{
+    /// Serialise this `LexerDef` into a compact binary form which can be loaded again with
+    /// [`from_cache`](#method.from_cache). Returns an error if a rule ID does not fit in a
+    /// `usize`. Only available with the `cache` feature.
+    pub fn to_cache(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut out = CACHE_MAGIC.to_vec();
+        write_uint(&mut out, CACHE_VERSION);
+        write_uint(&mut out, self.rules.len() as u64);
+        for r in &self.rules {
+            match r.tok_id {
+                Some(id) => {
+                    out.push(1);
+                    let id = usize::try_from(id)
+                                   .map_err(|_| "Rule ID does not fit in a usize")?;
+                    write_uint(&mut out, id as u64);
+                },
+                None => out.push(0)
+            }
+            write_opt_str(&mut out, r.name.as_deref());
+            write_str(&mut out, &r.re_str);
+            match r.nest_limit {
+                Some(l) => {
+                    out.push(1);
+                    write_uint(&mut out, u64::from(l));
+                },
+                None => out.push(0)
+            }
+            out.push(u8::from(r.multi_line));
+            write_opt_str(&mut out, r.doc.as_deref());
+            write_opt_str(&mut out, r.category.as_deref());
+            write_opt_str(&mut out, r.error.as_deref());
+        }
+        Ok(out)
+    }
+}
+
+impl<TokId: Copy + Eq + TryFrom<usize>> LexerDef<TokId> {
+    /// Load a `LexerDef` from a cache created by [`to_cache`](#method.to_cache). Returns an
+    /// error if `b` is not a valid cache, if it was created by a different version of lrlex's
+    /// cache format, or if a rule ID does not fit in `TokId`. Only available with the `cache`
+    /// feature.
+    pub fn from_cache(b: &[u8]) -> Result<LexerDef<TokId>, Box<dyn Error>> {
+        if !b.starts_with(CACHE_MAGIC) {
+            return Err("Not an lrlex cache".into());
+        }
+        let mut i = CACHE_MAGIC.len();
+        let version = read_uint(b, &mut i)?;
+        if version != CACHE_VERSION {
+            return Err(format!("Cache has version {} but version {} is required",
+                               version,
+                               CACHE_VERSION).into());
+        }
+        let num_rules = read_uint(b, &mut i)?;
+        let mut rules = Vec::new();
+        for _ in 0..num_rules {
+            let tok_id = match read_byte(b, &mut i)? {
+                0 => None,
+                1 => {
+                    let id = read_uint(b, &mut i)? as usize;
+                    Some(TokId::try_from(id).map_err(|_| format!("Rule ID {} is too big", id))?)
+                },
+                _ => return Err("Corrupt cache".into())
+            };
+            let name = read_opt_str(b, &mut i)?;
+            let re_str = read_str(b, &mut i)?;
+            let nest_limit = match read_byte(b, &mut i)? {
+                0 => None,
+                1 => {
+                    let l = read_uint(b, &mut i)?;
+                    Some(u32::try_from(l).map_err(|_| format!("Nest limit {} is too big", l))?)
+                },
+                _ => return Err("Corrupt cache".into())
+            };
+            let multi_line = match read_byte(b, &mut i)? {
+                0 => false,
+                1 => true,
+                _ => return Err("Corrupt cache".into())
+            };
+            let doc = read_opt_str(b, &mut i)?;
+            let category = read_opt_str(b, &mut i)?;
+            let error = read_opt_str(b, &mut i)?;
+            let mut rule = Rule::new_with_nest_limit(tok_id, name, re_str, nest_limit)?;
+            if !multi_line {
+                rule.set_multi_line(false)?;
+            }
+            rule.doc = doc;
+            rule.category = category;
+            rule.error = error;
+            rules.push(rule);
+        }
+        if i != b.len() {
+            return Err("Corrupt cache".into());
+        }
+        Ok(LexerDef::new(rules))
+    }
+}
+
+/// Append `n` to `out` as a LEB128 integer.
+fn write_uint(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let b = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(b);
+            return;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_uint(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_opt_str(out: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            out.push(1);
+            write_str(out, s);
+        },
+        None => out.push(0)
+    }
+}
+
+fn read_byte(b: &[u8], i: &mut usize) -> Result<u8, Box<dyn Error>> {
+    match b.get(*i) {
+        Some(&x) => {
+            *i += 1;
+            Ok(x)
+        },
+        None => Err("Truncated cache".into())
+    }
+}
+
+/// Read a LEB128 integer from `b` at offset `i`, advancing `i` past it.
+fn read_uint(b: &[u8], i: &mut usize) -> Result<u64, Box<dyn Error>> {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let x = read_byte(b, i)?;
+        if shift > 63 {
+            return Err("Corrupt cache".into());
+        }
+        n |= u64::from(x & 0x7f) << shift;
+        if x & 0x80 == 0 {
+            return Ok(n);
+        }
+        shift += 7;
+    }
+}
+
+fn read_str(b: &[u8], i: &mut usize) -> Result<String, Box<dyn Error>> {
+    let len = read_uint(b, i)? as usize;
+    if b.len() - *i < len {
+        return Err("Truncated cache".into());
+    }
+    let s = str::from_utf8(&b[*i..*i + len])?.to_string();
+    *i += len;
+    Ok(s)
+}
+
+fn read_opt_str(b: &[u8], i: &mut usize) -> Result<Option<String>, Box<dyn Error>> {
+    match read_byte(b, i)? {
+        0 => Ok(None),
+        1 => Ok(Some(read_str(b, i)?)),
+        _ => Err("Corrupt cache".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CACHE_VERSION;
+    use lexer::{LexerDef, Lexeme};
+    use parser::{parse_lex_with_id_base, parse_lex_with_nest_limit};
+
+    #[test]
+    fn test_round_trip() {
+        let src = "%%
+%doc 'An identifier'
+[a-z]+ 'ID'
+%category 'string'
+\"[^\"]*\" 'STR'
+%error 'Unexpected digit'
+[0-9] ;
+[ ] ;".to_string();
+        let lexerdef = parse_lex_with_id_base::<u16>(&src, 300).unwrap();
+        let c = lexerdef.to_cache().unwrap();
+        let lexerdef2 = LexerDef::<u16>::from_cache(&c).unwrap();
+        assert_eq!(lexerdef2.iter_rules().count(), 4);
+        assert_eq!(lexerdef2.get_rule_by_name("ID").unwrap().doc,
+                   Some("An identifier".to_string()));
+        assert_eq!(lexerdef2.get_rule_by_name("STR").unwrap().category, Some("string".to_string()));
+        assert_eq!(lexerdef2.get_rule(2).unwrap().error, Some("Unexpected digit".to_string()));
+        assert_eq!(lexerdef2.fingerprint(), lexerdef.fingerprint());
+        assert_eq!(lexerdef2.lexer("a \"b\"").lexemes().unwrap(),
+                   vec![Lexeme::new(300, 0, 1), Lexeme::new(301, 2, 3)]);
+
+        assert!(LexerDef::<u8>::from_cache(&c).is_err());
+        assert!(LexerDef::<u16>::from_cache(&c[..c.len() - 1]).is_err());
+        let mut c2 = c.clone();
+        c2[4] = CACHE_VERSION as u8 + 1;
+        assert!(LexerDef::<u16>::from_cache(&c2).is_err());
+    }
+
+    #[test]
+    fn test_nest_limit() {
+        // This regex can only be compiled with a raised nesting limit.
+        let src = format!("%%\n{}a{} 'A'", "(".repeat(300), ")".repeat(300));
+        let lexerdef = parse_lex_with_nest_limit::<u8>(&src, 0, Some(1000)).unwrap();
+        let lexerdef2 = LexerDef::<u8>::from_cache(&lexerdef.to_cache().unwrap()).unwrap();
+        assert_eq!(lexerdef2.lexer("a").lexemes().unwrap(), vec![Lexeme::new(0, 0, 1)]);
+    }
+
+    #[test]
+    fn test_multi_line() {
+        let src = "%%\n[a-z]+$ 'EOL'\n[a-z]+ 'ID'\n\\n ;";
+        let mut lexerdef = parse_lex_with_id_base::<u8>(src, 0).unwrap();
+        lexerdef.set_multi_line(false).unwrap();
+        let lexerdef2 = LexerDef::<u8>::from_cache(&lexerdef.to_cache().unwrap()).unwrap();
+        assert_eq!(lexerdef2.fingerprint(), lexerdef.fingerprint());
+        assert_eq!(lexerdef2.lexer("ab\ncd").lexemes().unwrap(),
+                   vec![Lexeme::new(1, 0, 2), Lexeme::new(0, 3, 2)]);
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright (c) 2017 King's College London
+// created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any person obtaining a
+// copy of this software, associated documentation and/or data (collectively the "Software"), free
+// of charge and under any and all copyright rights in the Software, and any and all patent rights
+// owned or freely licensable by each licensor hereunder covering either (i) the unmodified
+// Software as contributed to or provided by such licensor, or (ii) the Larger Works (as defined
+// below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software is contributed
+// by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create derivative works
+// of, display, perform, and distribute the Software and make, use, sell, offer for sale, import,
+// export, have made, and have sold the Software and the Larger Work(s), and to sublicense the
+// foregoing rights on either these or other terms.
+//
+// This license is subject to the following condition: The above copyright notice and either this
+// complete permission notice or at a minimum a reference to the UPL must be included in all copies
+// or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use std::char;
+
+/// The escape conventions which can be decoded by
+/// [`Lexer::decode`](struct.Lexer.html#method.decode).
+#[derive(Clone, Copy, Debug, Hash, PartialEq)]
+pub enum EscapeStyle {
+    /// C-style escapes: `\a`, `\b`, `\f`, `\n`, `\r`, `\t`, `\v`, `\\`, `\'`, `\"`, `\?`, octal
+    /// escapes of 1-3 digits (e.g. `\0`, `\177`), `\xHH`, `\uHHHH`, and `\UHHHHHHHH`.
+    C,
+    /// JSON escapes: `\"`, `\\`, `\/`, `\b`, `\f`, `\n`, `\r`, `\t`, and `\uHHHH` (where a high
+    /// surrogate must be immediately followed by a `\uHHHH` low surrogate).
+    Json,
+    /// Rust escapes: `\n`, `\r`, `\t`, `\\`, `\0`, `\'`, `\"`, `\xHH` (at most `\x7F`), and
+    /// `\u{H}` to `\u{HHHHHH}`.
+    Rust
+}
+
+/// Decode the escapes in `s` according to `style`. If an invalid escape is encountered, returns
+/// `Err((off, len))` where `off` is the byte offset of the invalid escape's backslash in `s` and
+/// `len` the length in bytes of the invalid escape.
+pub(crate) fn decode(style: EscapeStyle, s: &str) -> Result<String, (usize, usize)> {
+    let mut out = String::with_capacity(s.len());
+    let mut i = 0;
+    while let Some(j) = s[i..].find('\\') {
+        out.push_str(&s[i..i + j]);
+        i += j;
+        let (c, len) = match style {
+            EscapeStyle::C => decode_c(&s[i..]),
+            EscapeStyle::Json => decode_json(&s[i..]),
+            EscapeStyle::Rust => decode_rust(&s[i..])
+        }.map_err(|len| (i, len))?;
+        out.push(c);
+        i += len;
+    }
+    out.push_str(&s[i..]);
+    Ok(out)
+}
+
+/// Decode the C escape at the start of `s` (which must start with a backslash), returning the
+/// decoded character and the length in bytes of the escape, or the length in bytes of the
+/// invalid escape.
+fn decode_c(s: &str) -> Result<(char, usize), usize> {
+    let c = match s[1..].chars().next() {
+        Some(c) => c,
+        None => return Err(1)
+    };
+    let simple = match c {
+        'a' => '\x07',
+        'b' => '\x08',
+        'f' => '\x0c',
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        'v' => '\x0b',
+        '\\' | '\'' | '"' | '?' => c,
+        '0' ..= '7' => {
+            let len = s[1..].chars().take(3).take_while(|c| c.is_digit(8)).count();
+            let v = u32::from_str_radix(&s[1..1 + len], 8).unwrap();
+            return char::from_u32(v).map(|c| (c, 1 + len)).ok_or(1 + len);
+        },
+        'x' => return decode_hex(s, 2, 2),
+        'u' => return decode_hex(s, 2, 4),
+        'U' => return decode_hex(s, 2, 8),
+        _ => return Err(1 + c.len_utf8())
+    };
+    Ok((simple, 2))
+}
+
+/// Decode the JSON escape at the start of `s`: see `decode_c`.
+fn decode_json(s: &str) -> Result<(char, usize), usize> {
+    let c = match s[1..].chars().next() {
+        Some(c) => c,
+        None => return Err(1)
+    };
+    let simple = match c {
+        'b' => '\x08',
+        'f' => '\x0c',
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '"' | '\\' | '/' => c,
+        'u' => {
+            let v = hex_value(s, 2, 4)?;
+            if (0xD800..0xDC00).contains(&v) {
+                // A high surrogate must be followed by a low surrogate.
+                if !s[6..].starts_with("\\u") {
+                    return Err(6);
+                }
+                let lo = hex_value(&s[6..], 2, 4).map_err(|j| 6 + j)?;
+                if !(0xDC00..0xE000).contains(&lo) {
+                    return Err(12);
+                }
+                let v = 0x10000 + ((v - 0xD800) << 10) + (lo - 0xDC00);
+                return Ok((char::from_u32(v).unwrap(), 12));
+            }
+            return char::from_u32(v).map(|c| (c, 6)).ok_or(6);
+        },
+        _ => return Err(1 + c.len_utf8())
+    };
+    Ok((simple, 2))
+}
+
+/// Decode the Rust escape at the start of `s`: see `decode_c`.
+fn decode_rust(s: &str) -> Result<(char, usize), usize> {
+    let c = match s[1..].chars().next() {
+        Some(c) => c,
+        None => return Err(1)
+    };
+    let simple = match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        '\\' | '\'' | '"' => c,
+        'x' => {
+            let (c, len) = decode_hex(s, 2, 2)?;
+            if c > '\x7f' {
+                return Err(len);
+            }
+            return Ok((c, len));
+        },
+        'u' => {
+            if !s[2..].starts_with('{') {
+                return Err(2);
+            }
+            let end = match s[3..].find('}') {
+                Some(j) if j > 0 && j <= 6 => 3 + j,
+                _ => return Err(3)
+            };
+            return u32::from_str_radix(&s[3..end], 16).ok()
+                                                      .and_then(char::from_u32)
+                                                      .map(|c| (c, end + 1))
+                                                      .ok_or(end + 1);
+        },
+        _ => return Err(1 + c.len_utf8())
+    };
+    Ok((simple, 2))
+}
+
+/// Decode the `n` hex digits starting at byte offset `i` in `s` as a character, returning the
+/// character and `i + n`.
+fn decode_hex(s: &str, i: usize, n: usize) -> Result<(char, usize), usize> {
+    let v = hex_value(s, i, n)?;
+    char::from_u32(v).map(|c| (c, i + n)).ok_or(i + n)
+}
+
+/// Return the value of the `n` hex digits starting at byte offset `i` in `s`.
+fn hex_value(s: &str, i: usize, n: usize) -> Result<u32, usize> {
+    let digits = s[i..].chars().take(n).take_while(|c| c.is_ascii_hexdigit()).count();
+    if digits != n {
+        return Err(i + digits);
+    }
+    Ok(u32::from_str_radix(&s[i..i + n], 16).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_c() {
+        assert_eq!(decode(EscapeStyle::C, r#"a\tb\"\\\101\x42\u00e9\0"#),
+                   Ok("a\tb\"\\AB\u{e9}\0".to_string()));
+        assert_eq!(decode(EscapeStyle::C, r"ab\q"), Err((2, 2)));
+        assert_eq!(decode(EscapeStyle::C, r"ab\x4"), Err((2, 3)));
+        assert_eq!(decode(EscapeStyle::C, r"ab\"), Err((2, 1)));
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(decode(EscapeStyle::Json, r#"\"a\/b\n\u00e9\ud83d\ude00"#),
+                   Ok("\"a/b\n\u{e9}\u{1f600}".to_string()));
+        assert_eq!(decode(EscapeStyle::Json, r"a\x41"), Err((1, 2)));
+        assert_eq!(decode(EscapeStyle::Json, r"a\ud83d b"), Err((1, 6)));
+        assert_eq!(decode(EscapeStyle::Json, r"a\ude00"), Err((1, 6)));
+    }
+
+    #[test]
+    fn test_rust() {
+        assert_eq!(decode(EscapeStyle::Rust, r"\x41\u{e9}\u{1F600}\0\'"),
+                   Ok("A\u{e9}\u{1f600}\0'".to_string()));
+        assert_eq!(decode(EscapeStyle::Rust, r"\x80"), Err((0, 4)));
+        assert_eq!(decode(EscapeStyle::Rust, r"a\u{}"), Err((1, 3)));
+        assert_eq!(decode(EscapeStyle::Rust, r"a\u{d800}"), Err((1, 8)));
+        assert_eq!(decode(EscapeStyle::Rust, r"\a"), Err((0, 2)));
+    }
+}
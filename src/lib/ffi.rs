@@ -0,0 +1,197 @@
+// Copyright (c) 2017 King's College London
+// created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any person obtaining a
+// copy of this software, associated documentation and/or data (collectively the "Software"), free
+// of charge and under any and all copyright rights in the Software, and any and all patent rights
+// owned or freely licensable by each licensor hereunder covering either (i) the unmodified
+// Software as contributed to or provided by such licensor, or (ii) the Larger Works (as defined
+// below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software is contributed
+// by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create derivative works
+// of, display, perform, and distribute the Software and make, use, sell, offer for sale, import,
+// export, have made, and have sold the Software and the Larger Work(s), and to sublicense the
+// foregoing rights on either these or other terms.
+//
+// This license is subject to the following condition: The above copyright notice and either this
+// complete permission notice or at a minimum a reference to the UPL must be included in all copies
+// or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// A C-friendly interface to lexing, enabled by the `ffi` feature. Lexemes are returned as three
+// parallel arrays (token IDs, start offsets, and lengths) allocated by Rust: they must be freed
+// with `lrlex_lexemes_free`, not with C's `free`.
+
+use std::ptr;
+use std::slice;
+use std::str;
+
+use lexer::{LexError, LexerDef};
+
+/// The lexemes produced by [`lex_to_arrays`](fn.lex_to_arrays.html) or `lrlex_lex`, as `len`
+/// parallel entries in the arrays `ids`, `starts` (byte offsets into the input), and `lens`
+/// (lengths in bytes). The arrays are owned by this struct, which must be released with
+/// `lrlex_lexemes_free` exactly once. An empty `LexemeArrays` has null pointers.
+#[repr(C)]
+#[derive(Debug)]
+pub struct LexemeArrays {
+    pub ids: *mut u32,
+    pub starts: *mut usize,
+    pub lens: *mut usize,
+    pub len: usize
+}
+
+impl LexemeArrays {
+    fn empty() -> LexemeArrays {
+        LexemeArrays{ids: ptr::null_mut(), starts: ptr::null_mut(), lens: ptr::null_mut(), len: 0}
+    }
+}
+
+/// Convert `v` into a raw pointer to its elements, which must later be freed with `free_raw`
+/// (passing `v`'s length).
+fn into_raw<T>(v: Vec<T>) -> *mut T {
+    if v.is_empty() {
+        return ptr::null_mut();
+    }
+    Box::into_raw(v.into_boxed_slice()) as *mut T
+}
+
+/// Free the `len` elements at `p`, which must have been returned by `into_raw`.
+unsafe fn free_raw<T>(p: *mut T, len: usize) {
+    if !p.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(p, len)));
+    }
+}
+
+/// Lex `s` with `lexerdef`, returning the lexemes as a `LexemeArrays` (which must be released
+/// with `lrlex_lexemes_free`), or the first lexing error.
+pub fn lex_to_arrays<TokId>(lexerdef: &LexerDef<TokId>, s: &str)
+                         -> Result<LexemeArrays, LexError<TokId>>
+                  where TokId: Copy + Eq + Into<u32>
+{
+    let lexemes = lexerdef.lexer(s).lexemes()?;
+    let len = lexemes.len();
+    let ids = lexemes.iter().map(|l| l.tok_id().into()).collect();
+    let starts = lexemes.iter().map(|l| l.start()).collect();
+    let lens = lexemes.iter().map(|l| l.len()).collect();
+    Ok(LexemeArrays{ids: into_raw(ids), starts: into_raw(starts), lens: into_raw(lens), len})
+}
+
+/// Lex the `s_len` bytes at `s` with the `LexerDef` at `lexerdef`, writing the lexemes to `out`.
+/// Returns 0 on success; 1 if there was a lexing error, in which case the byte offset of the
+/// error is written to `err_idx` (if it is not null); or 2 if the input is not valid UTF-8. On
+/// failure, `out` is set to an empty `LexemeArrays`. `out` must always be released with
+/// `lrlex_lexemes_free`.
+///
+/// # Safety
+///
+/// `lexerdef` must point to a valid `LexerDef<u32>` which is not mutated during the call. `s`
+/// must point to `s_len` initialised bytes which are readable for the duration of the call (if
+/// `s_len` is 0, `s` must still be non-null and suitably aligned, e.g. a dangling pointer). `out`
+/// must be a valid pointer to writable memory for a `LexemeArrays`: any arrays it already owns
+/// are overwritten, not freed. `err_idx` must either be null or a valid pointer to a writable
+/// `usize`. On return, the caller owns the arrays in `*out`, and must free them exactly once with
+/// `lrlex_lexemes_free`.
+#[no_mangle]
+pub unsafe extern "C" fn lrlex_lex(lexerdef: *const LexerDef<u32>,
+                                   s: *const u8,
+                                   s_len: usize,
+                                   out: *mut LexemeArrays,
+                                   err_idx: *mut usize)
+                                -> i32
+{
+    *out = LexemeArrays::empty();
+    let s = match str::from_utf8(slice::from_raw_parts(s, s_len)) {
+        Ok(s) => s,
+        Err(_) => return 2
+    };
+    match lex_to_arrays(&*lexerdef, s) {
+        Ok(a) => {
+            *out = a;
+            0
+        },
+        Err(e) => {
+            if !err_idx.is_null() {
+                *err_idx = e.idx();
+            }
+            1
+        }
+    }
+}
+
+/// Free the arrays owned by the `LexemeArrays` at `a`, leaving it empty (so freeing it again is
+/// harmless).
+///
+/// # Safety
+///
+/// `a` must be a valid, non-null pointer to a `LexemeArrays` which was either produced by
+/// `lrlex_lex` or is empty, and whose fields have not been modified since: in particular, its
+/// `len` must still be the length of each of its arrays. After this call, any copies of its
+/// array pointers are dangling.
+#[no_mangle]
+pub unsafe extern "C" fn lrlex_lexemes_free(a: *mut LexemeArrays) {
+    let a = &mut *a;
+    free_raw(a.ids, a.len);
+    free_raw(a.starts, a.len);
+    free_raw(a.lens, a.len);
+    *a = LexemeArrays::empty();
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::mem;
+    use std::slice;
+
+    use super::*;
+    use parser::parse_lex;
+
+    #[test]
+    fn test_lex_to_arrays() {
+        let src = "%%
+[a-z]+ 'ID'
+[0-9]+ 'INT'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex::<u32>(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 7);
+        map.insert("INT", 8);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "ab 123";
+        let mut out = lex_to_arrays(&lexerdef, s).unwrap();
+        unsafe {
+            assert_eq!(slice::from_raw_parts(out.ids, out.len), &[7, 8]);
+            assert_eq!(slice::from_raw_parts(out.starts, out.len), &[0, 3]);
+            assert_eq!(slice::from_raw_parts(out.lens, out.len), &[2, 3]);
+            lrlex_lexemes_free(&mut out);
+            assert!(out.ids.is_null() && out.len == 0);
+            lrlex_lexemes_free(&mut out);
+
+            let mut err_idx = 0;
+            let s = "ab $";
+            let mut out = mem::zeroed();
+            assert_eq!(lrlex_lex(&lexerdef, s.as_ptr(), s.len(), &mut out, &mut err_idx), 1);
+            assert_eq!(err_idx, 3);
+            assert_eq!(out.len, 0);
+            let s = b"ab \xff";
+            assert_eq!(lrlex_lex(&lexerdef, s.as_ptr(), s.len(), &mut out, &mut err_idx), 2);
+            let s = "";
+            assert_eq!(lrlex_lex(&lexerdef, s.as_ptr(), s.len(), &mut out, &mut err_idx), 0);
+            assert!(out.ids.is_null() && out.len == 0);
+            lrlex_lexemes_free(&mut out);
+        }
+    }
+}
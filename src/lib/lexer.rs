@@ -30,15 +30,44 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::mem;
 use std::rc::Rc;
 use std::slice::Iter;
+use std::str::{self, Utf8Error};
+use std::time::Instant;
 
+#[cfg(feature = "proc-macro2")]
+use proc_macro2::LineColumn;
 use regex;
 use regex::{Regex, RegexBuilder};
 
+use {LexBuildError, LexBuildResult, LexErrorKind};
+use escapes::{decode, EscapeStyle};
+use scanner;
+
+/// The pseudo-regex which marks a rule as matching the remainder of the input (see
+/// `Rule::is_rest`).
+const REST_RULE: &str = "%rest";
+/// The pseudo-regex which marks a rule as matching the end of the input (see `Rule::is_eof`).
+const EOF_RULE: &str = "%eof";
+/// The pseudo-regex which marks a rule as matching an indented block (see
+/// `Rule::is_block_indent`).
+const BLOCK_INDENT_RULE: &str = "%block-indent";
+/// How many matching steps a lexer with a deadline takes between checks of the clock.
+const DEADLINE_CHECK_INTERVAL: usize = 64;
+/// The default maximum nesting depth of a `Balanced`'s delimiters.
+const DEFAULT_MAX_DEPTH: usize = 1024;
+
+/// Lexemes paired with their decoded text (see `Lexer::lexemes_decoded`).
+type DecodedLexemes<TokId> = Vec<(Lexeme<TokId>, Option<String>)>;
+
+/// A single rule of a lexer definition. A `.l` file can only specify a rule's name, regex,
+/// `%doc`, `%category`, and `%error`: all of its other fields (e.g. `cont`, `modes`, or
+/// `balanced`) can only be set at run-time, and are not preserved by `process_file`.
 pub struct Rule<TokId> {
     /// If `Some`, the ID that lexemes created against this rule will be given (lrlex gives such
     /// rules a guaranteed unique value, though that value can be overridden by clients who need to
@@ -49,7 +78,84 @@ pub struct Rule<TokId> {
     /// create a lexeme).
     pub name: Option<String>,
     pub re_str: String,
-    pub re: Regex
+    pub re: Regex,
+    /// If `Some`, then after this rule's regex has matched a non-empty string, the match is
+    /// extended for as long as subsequent characters satisfy this predicate. This allows e.g.
+    /// Unicode identifiers to be matched with a regex for the start character and a function
+    /// such as `unicode_ident::is_xid_continue` for the remaining characters.
+    pub cont: Option<fn(char) -> bool>,
+    /// If `Some`, the escape convention used to decode the text of lexemes matched by this rule
+    /// (see [`Lexer::decode`](struct.Lexer.html#method.decode)).
+    pub escapes: Option<EscapeStyle>,
+    /// A description of this rule, as given by a `%doc` directive. This is emitted as a doc
+    /// comment on the corresponding token constant by `process_file`.
+    pub doc: Option<String>,
+    /// This rule's highlighting category (e.g. `keyword`, `string`, or `comment`), as given by a
+    /// `%category` directive (see
+    /// [`Lexer::highlight_spans`](struct.Lexer.html#method.highlight_spans)).
+    pub category: Option<String>,
+    /// If `Some`, an error message, as given by an `%error` directive: input matched by this rule
+    /// (whether or not it is named) then results in a `LexError` of kind
+    /// [`Rule`](enum.LexErrorCause.html#variant.Rule) rather than a lexeme. This allows fallback
+    /// rules to give specific diagnostics (e.g. `unterminated string literal`).
+    pub error: Option<String>,
+    /// If `Some`, the modes in which this rule is active: when the lexer is in any other mode,
+    /// this rule is ignored. If `None`, this rule is active in every mode. Modes are arbitrary
+    /// `usize` values (e.g. a user-defined `enum` converted with `as usize`): a lexer starts in
+    /// the mode set by [`Lexer::initial_mode`](struct.Lexer.html#method.initial_mode) and
+    /// changes mode only when a rule with a `set_mode` matches.
+    pub modes: Option<Vec<usize>>,
+    /// If `Some(m)`, the lexer switches to mode `m` after this rule matches (whether or not the
+    /// rule is named), so that subsequent matching only considers rules active in `m`.
+    pub set_mode: Option<usize>,
+    /// If `Some`, this predicate is called with the text this rule matches (after any
+    /// continuation). If it returns `true`, the match is rejected: the lexer then behaves as if
+    /// this rule had not matched, so the next best candidate (as per the lexer's
+    /// [`MatchPolicy`](enum.MatchPolicy.html)) wins instead. Unlike flex's `REJECT`, shorter
+    /// matches of the same rule are not considered.
+    pub reject: Option<fn(&str) -> bool>,
+    /// If `Some(n)`, the maximum length in bytes of lexemes matched by this rule: a longer match
+    /// results in a `LexError` of kind [`TooLong`](enum.LexErrorCause.html#variant.TooLong)
+    /// rather than a lexeme. This is useful when lexing untrusted input, where only certain kinds
+    /// of token (e.g. string literals) should be allowed to be long.
+    pub max_len: Option<usize>,
+    /// If `Some`, this rule is only considered if this predicate returns `true` when called with
+    /// the ID of the previous lexeme produced by the lexer, or `None` at the start of the input.
+    /// Input matched by unnamed rules, and lexing errors, do not count as lexemes for this
+    /// purpose. This allows e.g. `-` to be lexed as a unary or binary operator depending on the
+    /// preceding token.
+    pub after: Option<fn(Option<TokId>) -> bool>,
+    /// If `true`, this rule's matches are not limited by the lexer's
+    /// [`match_window`](struct.Lexer.html#method.match_window) (e.g. for rules which are expected
+    /// to match long strings, such as block comments). Defaults to `false`.
+    pub unbounded_match: bool,
+    /// If `Some`, this function is called with this rule's ID and the number of lexemes
+    /// previously produced by this rule (since the lexer started or was
+    /// [`reset`](struct.Lexer.html#method.reset)), and returns the ID of the lexeme to produce.
+    /// This allows e.g. a rule to produce a distinct "duplicate" token for its second and
+    /// subsequent matches.
+    pub id_for_count: Option<fn(TokId, usize) -> TokId>,
+    /// If `Some(g)`, this rule is in the mutually exclusive group `g`: once any rule in `g` has
+    /// matched, the other rules in `g` are disabled for the rest of the input (until the lexer is
+    /// [`reset`](struct.Lexer.html#method.reset)). This allows e.g. a lexer to commit to one
+    /// dialect of a language when it first sees a token distinctive of that dialect. Groups are
+    /// arbitrary `usize` values.
+    pub group: Option<usize>,
+    /// If `Some`, then after this rule's regex has matched a string ending in an opening
+    /// delimiter (e.g. `\(` or `[a-z]+!\(`), the match is extended up to and including the
+    /// closing delimiter which balances it (see [`Balanced`](struct.Balanced.html)), before any
+    /// continuation. If the input (or match window) ends first, the rule's match covers the rest
+    /// of it and, if it wins, results in a `LexError` of kind
+    /// [`Unbalanced`](enum.LexErrorCause.html#variant.Unbalanced).
+    pub balanced: Option<Balanced>,
+    /// If `Some`, the only string this rule's regex can match, which the lexer then matches
+    /// without using the regex engine.
+    literal: Option<String>,
+    /// The nesting limit this rule's regex was compiled with, so that it can be recompiled (see
+    /// `LexerDef::set_multi_line`).
+    pub(crate) nest_limit: Option<u32>,
+    /// Was this rule's regex compiled in multi-line mode (see `LexerDef::set_multi_line`)?
+    pub(crate) multi_line: bool
 }
 
 impl<TokId> Rule<TokId> {
@@ -61,17 +167,299 @@ impl<TokId> Rule<TokId> {
            re_str: String)
         -> Result<Rule<TokId>, regex::Error>
     {
-        let re = RegexBuilder::new(&format!("\\A(?:{})", &re_str))
-                              .multi_line(true)
-                              .dot_matches_new_line(true)
-                              .build()?;
-        Ok(Rule{tok_id, name, re_str, re})
+        Rule::new_with_nest_limit(tok_id, name, re_str, None)
+    }
+
+    /// As `new`, but if `nest_limit` is `Some`, the regex is compiled with that nesting limit
+    /// rather than the `regex` crate's default. This interface is unstable and should only be
+    /// used by code generated by lrlex itself.
+    #[doc(hidden)]
+    pub fn new_with_nest_limit(tok_id: Option<TokId>,
+                               name: Option<String>,
+                               re_str: String,
+                               nest_limit: Option<u32>)
+                            -> Result<Rule<TokId>, regex::Error>
+    {
+        let re = Rule::<TokId>::compile(&re_str, nest_limit, true)?;
+        let literal = if re_str == REST_RULE || re_str == EOF_RULE || re_str == BLOCK_INDENT_RULE {
+            None
+        } else {
+            scanner::literal(&re_str)
+        };
+        Ok(Rule{tok_id,
+                name,
+                re_str,
+                re,
+                cont: None,
+                escapes: None,
+                doc: None,
+                category: None,
+                error: None,
+                modes: None,
+                set_mode: None,
+                reject: None,
+                max_len: None,
+                after: None,
+                unbounded_match: false,
+                id_for_count: None,
+                group: None,
+                balanced: None,
+                literal,
+                nest_limit,
+                multi_line: true})
+    }
+
+    /// Recompile this rule's regex in multi-line mode if `multi_line` is `true`, or not
+    /// otherwise (see `LexerDef::set_multi_line`).
+    pub(crate) fn set_multi_line(&mut self, multi_line: bool) -> Result<(), regex::Error> {
+        self.re = Rule::<TokId>::compile(&self.re_str, self.nest_limit, multi_line)?;
+        self.multi_line = multi_line;
+        Ok(())
+    }
+
+    /// Compile the regex for a rule whose regex string is `re_str`, anchoring it to the start of
+    /// the input.
+    fn compile(re_str: &str, nest_limit: Option<u32>, multi_line: bool)
+            -> Result<Regex, regex::Error>
+    {
+        let mut rb = if re_str == REST_RULE {
+            RegexBuilder::new("\\A(?:.+)")
+        } else if re_str == EOF_RULE || re_str == BLOCK_INDENT_RULE {
+            RegexBuilder::new("\\A\\z")
+        } else {
+            RegexBuilder::new(&format!("\\A(?:{})", re_str))
+        };
+        rb.multi_line(multi_line)
+          .dot_matches_new_line(true);
+        if let Some(l) = nest_limit {
+            rb.nest_limit(l);
+        }
+        rb.build()
+    }
+
+    /// Create an anonymous rule matching runs of ASCII whitespace (spaces, tabs, carriage returns,
+    /// and newlines), which the lexer therefore skips.
+    pub fn whitespace() -> Rule<TokId> {
+        Rule::new(None, None, "[ \\t\\r\\n]+".to_string()).unwrap()
+    }
+
+    /// Create a rule called `name` matching C-style identifiers (an ASCII letter or underscore
+    /// followed by any number of ASCII letters, digits, or underscores). The rule has no token
+    /// ID: this is set by [`LexerDef::set_rule_ids`](struct.LexerDef.html#method.set_rule_ids).
+    pub fn c_identifier(name: &str) -> Rule<TokId> {
+        Rule::new(None, Some(name.to_string()), "[a-zA-Z_][a-zA-Z0-9_]*".to_string()).unwrap()
+    }
+
+    /// Create a rule called `name` matching C-style integer literals (decimal, octal, or `0x`/`0X`
+    /// hexadecimal, with an optional run of `u`/`U`/`l`/`L` suffixes). The rule has no token ID:
+    /// this is set by [`LexerDef::set_rule_ids`](struct.LexerDef.html#method.set_rule_ids).
+    pub fn c_integer(name: &str) -> Rule<TokId> {
+        Rule::new(None, Some(name.to_string()),
+                  "(?:0[xX][0-9a-fA-F]+|[0-9]+)[uUlL]*".to_string()).unwrap()
+    }
+
+    /// If this rule's regex contains no metacharacters (other than escaped ones, e.g. `\+`) and
+    /// so can match only one string, return that string. Such rules are matched by simple string
+    /// comparison rather than by the regex engine, so there is no performance penalty for writing
+    /// literal tokens as regexes; this method allows tools to find such rules (e.g. to warn about
+    /// them, or to group them into a keyword table).
+    pub fn literal(&self) -> Option<&str> {
+        self.literal.as_deref()
+    }
+
+    /// Is this a `%rest` rule? Such rules are only considered when no other rule matches at the
+    /// current position: they then consume the entire remainder of the input as a single lexeme.
+    /// Like other rules, a `%rest` rule is only considered in its [`modes`](#structfield.modes)
+    /// (e.g. to treat everything after an `__END__` marker as data): if several are active in the
+    /// current mode, the first one wins.
+    pub fn is_rest(&self) -> bool {
+        self.re_str == REST_RULE
+    }
+
+    /// Is this a `%eof` rule? Such rules match only at the end of the input, producing a lexeme
+    /// of length 0 (e.g. to give a parser an explicit end-of-input token). No other rules are
+    /// tried at the end of the input, and at most one `%eof` rule (the first one active in the
+    /// lexer's current mode) fires, exactly once, whether or not the input is empty.
+    pub fn is_eof(&self) -> bool {
+        self.re_str == EOF_RULE
+    }
+
+    /// Is this a `%block-indent` rule? Such rules match the rest of the current line together with
+    /// all following lines which are indented more deeply than the current line (i.e. an
+    /// indentation-delimited block, as in YAML's `key: |` block scalars). Blank lines within the
+    /// block are included, but blank lines at its end are not, nor is the newline which ends the
+    /// block. A line's indentation is the number of spaces and tabs at its start: each counts as
+    /// one column, so blocks should be indented consistently with either spaces or tabs. A
+    /// `%block-indent` rule is usually restricted (e.g. with
+    /// [`after`](#structfield.after)) to the contexts in which blocks can occur.
+    pub fn is_block_indent(&self) -> bool {
+        self.re_str == BLOCK_INDENT_RULE
+    }
+
+    /// Is this a pseudo-rule (i.e. `%rest`, `%eof`, or `%block-indent`) whose regex is a
+    /// placeholder?
+    pub(crate) fn is_pseudo(&self) -> bool {
+        self.is_rest() || self.is_eof() || self.is_block_indent()
+    }
+
+    /// Is this rule active in the lexer state `st` (i.e. in its mode, and after its previous
+    /// lexeme)?
+    fn active_in(&self, st: &LexState<TokId>) -> bool where TokId: Copy {
+        self.modes.as_ref().is_none_or(|m| m.contains(&st.mode))
+            && self.after.is_none_or(|f| f(st.prev))
+    }
+}
+
+/// A pair of delimiters which a rule's match is extended to balance (see
+/// [`Rule::balanced`](struct.Rule.html#structfield.balanced)): e.g. `Balanced::new('(', ')')`
+/// extends a match over `(a (b) c)` as a whole. Text matching one of the balancer's skip regexes
+/// (e.g. string literals) is passed over without its delimiters being counted. So that adversarial
+/// input cannot make the lexer scan arbitrarily deeply nested delimiters, nesting deeper than the
+/// balancer's [`max_depth`](#method.max_depth) (by default 1024) is an error.
+#[derive(Clone, Debug)]
+pub struct Balanced {
+    open: char,
+    close: char,
+    skips: Vec<Regex>,
+    max_depth: usize
+}
+
+impl Balanced {
+    /// Create a balancer for the delimiters `open` and `close`, which must differ.
+    pub fn new(open: char, close: char) -> Balanced {
+        assert_ne!(open, close);
+        Balanced{open, close, skips: Vec::new(), max_depth: DEFAULT_MAX_DEPTH}
+    }
+
+    /// Set the maximum depth to which delimiters may be nested, where the delimiter which starts
+    /// the balancing (i.e. the end of the rule's regex match) is at depth 1. Input nested any
+    /// deeper results in a [`NestingTooDeep`](enum.LexErrorCause.html#variant.NestingTooDeep)
+    /// error.
+    pub fn max_depth(mut self, max_depth: usize) -> Balanced {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Pass over any text matching the regex `re_str` (e.g. `"[^"]*"` for simple string
+    /// literals) while balancing delimiters. At each point, the longest match of any skip regex
+    /// is passed over; empty matches are ignored.
+    pub fn skip(mut self, re_str: &str) -> Result<Balanced, regex::Error> {
+        self.skips.push(Regex::new(&format!("\\A(?:{})", re_str))?);
+        Ok(self)
+    }
+
+    /// Return the offset in `t` just after the closing delimiter which balances an opening
+    /// delimiter ending at offset `j`, and `None`; or, if `t` ends first, `t.len()` and
+    /// `Some(Unbalanced)`; or, if the delimiters are nested too deeply, the offset just after the
+    /// opening delimiter which exceeds the maximum depth and `Some(NestingTooDeep)`.
+    fn balance<TokId: Copy>(&self, t: &str, mut j: usize) -> (usize, Option<LexErrorCause<TokId>>) {
+        let mut depth = 1usize;
+        while j < t.len() {
+            let skip = self.skips
+                           .iter()
+                           .filter_map(|re| re.find(&t[j..]))
+                           .map(|m| m.end())
+                           .max();
+            if let Some(len) = skip.filter(|&len| len > 0) {
+                j += len;
+                continue;
+            }
+            let c = t[j..].chars().next().unwrap();
+            j += c.len_utf8();
+            if c == self.open {
+                depth += 1;
+                if depth > self.max_depth {
+                    return (j, Some(LexErrorCause::NestingTooDeep));
+                }
+            } else if c == self.close {
+                depth -= 1;
+                if depth == 0 {
+                    return (j, None);
+                }
+            }
+        }
+        (t.len(), Some(LexErrorCause::Unbalanced))
+    }
+}
+
+/// The reason for a [`LexError`](struct.LexError.html).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LexErrorCause<TokId: Copy> {
+    /// No rule with an ID matches the input.
+    Unmatched,
+    /// A lexeme contains an invalid escape (see
+    /// [`Lexer::decode`](struct.Lexer.html#method.decode)).
+    InvalidEscape,
+    /// The input matches the rule with ID `id`, but is longer than that rule's
+    /// [`max_len`](struct.Rule.html#structfield.max_len).
+    TooLong { id: TokId },
+    /// The lexer's [`deadline`](struct.Lexer.html#method.deadline) passed. The error's `idx` is
+    /// the offset lexing had reached, and its `len` is 0.
+    Timeout,
+    /// The lexer would have produced more than its
+    /// [`max_lexemes`](struct.Lexer.html#method.max_lexemes). The error's `idx` is the start of
+    /// the first lexeme beyond the limit, and its `len` is 0.
+    TooManyTokens,
+    /// The input matches a rule with [`balanced`](struct.Rule.html#structfield.balanced)
+    /// delimiters, but ends before they are balanced. The error covers the rest of the input.
+    Unbalanced,
+    /// The input matches a rule with [`balanced`](struct.Rule.html#structfield.balanced)
+    /// delimiters, but they are nested more deeply than its
+    /// [`max_depth`](struct.Balanced.html#method.max_depth). The error covers the input up to,
+    /// and including, the first opening delimiter beyond that depth.
+    NestingTooDeep,
+    /// The input contains a NUL character, and the lexer's
+    /// [`NulPolicy`](enum.NulPolicy.html) is `Error`.
+    Nul,
+    /// The input matches the rule at index `ridx` (see
+    /// [`LexerDef::get_rule`](struct.LexerDef.html#method.get_rule)), which has an
+    /// [`error`](struct.Rule.html#structfield.error) message (see also
+    /// [`LexerDef::error_message`](struct.LexerDef.html#method.error_message)).
+    Rule { ridx: usize }
+}
+
+/// A lexing error, covering `len` bytes of the input starting at byte offset `idx`.
+#[derive(Debug)]
+pub struct LexError<TokId: Copy> {
+    idx: usize,
+    len: usize,
+    cause: LexErrorCause<TokId>
+}
+
+impl<TokId: Copy> LexError<TokId> {
+    /// The reason for this error.
+    pub fn cause(&self) -> LexErrorCause<TokId> {
+        self.cause
+    }
+
+    /// Byte offset of the start of the erroneous input.
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+
+    /// Length in bytes of the erroneous input.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Does this error cover no input (e.g. a [`Timeout`](enum.LexErrorCause.html#variant.Timeout)
+    /// error)?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 }
 
+/// The output of lexing in recovery mode (see
+/// [`Lexer::lex_all`](struct.Lexer.html#method.lex_all)).
 #[derive(Debug)]
-pub struct LexError {
-    idx: usize
+pub struct LexAllOutput<TokId: Copy> {
+    /// The lexemes successfully lexed, in input order.
+    pub lexemes: Vec<Lexeme<TokId>>,
+    /// The errors encountered, in input order.
+    pub errors: Vec<LexError<TokId>>,
+    /// `true` if more errors were encountered than were recorded in `errors` (see
+    /// [`Lexer::max_errors`](struct.Lexer.html#method.max_errors)).
+    pub errors_truncated: bool
 }
 
 /// This struct represents, in essence, a .l file in memory. From it one can produce a `Lexer`
@@ -90,6 +478,14 @@ impl<TokId: Copy + Eq> LexerDef<TokId> {
         self.rules.get(idx)
     }
 
+    /// Get the compiled regex of the `Rule` at index `idx`. Note that the regex is anchored to the
+    /// start of its input (i.e. `re_str` is wrapped as `\A(?:re_str)`) and is compiled with
+    /// multi-line and dot-matches-newline enabled. The regex is borrowed from this `LexerDef`:
+    /// callers who need a regex which outlives the `LexerDef` should `clone` it, which is cheap.
+    pub fn rule_regex(&self, idx: usize) -> Option<&Regex> {
+        self.rules.get(idx).map(|r| &r.re)
+    }
+
     /// Get the `Rule` instance associated with a particular lexeme ID. Panics if no such rule
     /// exists.
     pub fn get_rule_by_id(&self, tok_id: TokId) -> &Rule<TokId> {
@@ -101,6 +497,11 @@ impl<TokId: Copy + Eq> LexerDef<TokId> {
         self.rules.iter().find(|r| r.name.as_ref().map(|x| x.as_str()) == Some(n))
     }
 
+    /// Get a mutable reference to the `Rule` instance associated with a particular name.
+    pub fn get_rule_by_name_mut(&mut self, n: &str) -> Option<&mut Rule<TokId>> {
+        self.rules.iter_mut().find(|r| r.name.as_deref() == Some(n))
+    }
+
     /// Set the id attribute on rules to the corresponding value in `map`. This is typically used
     /// to synchronise a parser's notion of lexeme IDs with the lexers. While doing this, it keeps
     /// track of which lexemes:
@@ -167,251 +568,2835 @@ impl<TokId: Copy + Eq> LexerDef<TokId> {
         (missing_from_lexer, missing_from_parser)
     }
 
+    /// If `e` was caused by a rule with an `%error` directive (see
+    /// [`Rule::error`](struct.Rule.html#structfield.error)), return that rule's error message.
+    pub fn error_message(&self, e: &LexError<TokId>) -> Option<&str> {
+        match e.cause {
+            LexErrorCause::Rule{ridx} => {
+                self.get_rule(ridx).and_then(|r| r.error.as_ref()).map(|m| m.as_str())
+            },
+            _ => None
+        }
+    }
+
+    /// Set whether rules' regexes are compiled in multi-line mode, recompiling them all. In
+    /// multi-line mode (the default), `$` matches both before a `\n` and at the end of the input,
+    /// and `^` both after a `\n` and at the start of the input; otherwise, `$` matches only at
+    /// the end of the input and `^` only at its start. In both cases, the input is considered to
+    /// end at the end of the lexer's input (or match window), and `\r` is not a line terminator.
+    /// Note that since each rule is matched against the remaining input, `^` also matches
+    /// wherever a rule is tried, in either mode. If any regex fails to recompile (e.g. due to
+    /// the nesting limit it was compiled with), an error is returned and no rules are changed.
+    pub fn set_multi_line(&mut self, multi_line: bool) -> Result<(), regex::Error> {
+        let mut res = Vec::with_capacity(self.rules.len());
+        for r in &self.rules {
+            res.push(Rule::<TokId>::compile(&r.re_str, r.nest_limit, multi_line)?);
+        }
+        for (r, re) in self.rules.iter_mut().zip(res) {
+            r.re = re;
+            r.multi_line = multi_line;
+        }
+        Ok(())
+    }
+
     /// Returns an iterator over all rules in this AST.
     pub fn iter_rules(&self) -> Iter<Rule<TokId>> {
         self.rules.iter()
     }
 
+    /// Render the mode machine defined by this `LexerDef`'s rules (see
+    /// [`Rule::modes`](struct.Rule.html#structfield.modes)) as a GraphViz `digraph`. There is one
+    /// node for each mode mentioned by a rule (and for mode 0, the default initial mode), listing
+    /// the rules active in that mode in order; and one edge for each rule with a `set_mode`, from
+    /// each mode the rule is active in to the mode it switches to. Named rules are shown by name
+    /// and unnamed rules by their regex.
+    pub fn to_dot(&self) -> String {
+        let mut modes = BTreeSet::new();
+        modes.insert(0);
+        for r in &self.rules {
+            if let Some(ref ms) = r.modes {
+                modes.extend(ms.iter().cloned());
+            }
+            modes.extend(r.set_mode);
+        }
+        let desc = |r: &Rule<TokId>| {
+            let d = match r.name {
+                Some(ref n) => format!("'{}'", n),
+                None => r.re_str.clone()
+            };
+            d.replace("\\", "\\\\").replace("\"", "\\\"")
+        };
+        let active = |r: &Rule<TokId>, m| r.modes.as_ref().is_none_or(|ms| ms.contains(&m));
+
+        let mut outs = String::from("digraph lexer {\n");
+        for &m in &modes {
+            let mut label = format!("mode {}", m);
+            for r in self.rules.iter().filter(|r| active(r, m)) {
+                label.push_str("\\n");
+                label.push_str(&desc(r));
+            }
+            outs.push_str(&format!("    m{} [label=\"{}\"];\n", m, label));
+        }
+        for r in &self.rules {
+            if let Some(to) = r.set_mode {
+                for &m in modes.iter().filter(|&&m| active(r, m)) {
+                    outs.push_str(&format!("    m{} -> m{} [label=\"{}\"];\n", m, to, desc(r)));
+                }
+            }
+        }
+        outs.push_str("}\n");
+        outs
+    }
+
     /// Return a lexer for the `String` `s` that will lex relative to this `LexerDef`.
     pub fn lexer<'a>(&'a self, s: &'a str) -> Lexer<'a, TokId> {
         Lexer::new(self, s)
     }
-}
 
-/// A lexer holds a reference to a string and can lex it into `Lexeme`s. Although the struct is
-/// tied to a single string, no guarantees are made about whether the lexemes are cached or not.
-pub struct Lexer<'a, TokId: 'a> {
-    lexerdef: &'a LexerDef<TokId>,
-    s: &'a str,
-    newlines: Rc<RefCell<Vec<usize>>>
-}
+    /// Return a lexer which is fed its input one line at a time (see
+    /// [`LineLexer`](struct.LineLexer.html)).
+    pub fn line_lexer<'a>(&'a self) -> LineLexer<'a, TokId> {
+        LineLexer{lexerdef: self,
+                  buf: String::new(),
+                  base: 0,
+                  state: LexState::new(0)}
+    }
 
-impl<'a, TokId: Copy + Eq> Lexer<'a, TokId> {
-    fn new(lexerdef: &'a LexerDef<TokId>, s: &'a str) -> Lexer<'a, TokId> {
-        Lexer {lexerdef, s, newlines: Rc::new(RefCell::new(Vec::new()))}
+    /// Return a lexer which owns the input `s` (see [`OwnedLexer`](struct.OwnedLexer.html)).
+    pub fn owned_lexer<'a>(&'a self, s: String) -> OwnedLexer<'a, TokId> {
+        OwnedLexer{lexerdef: self, s}
     }
 
-    /// Return all this lexer's lexemes or a `LexError` if there was a problem when lexing.
-    pub fn lexemes(&self) -> Result<Vec<Lexeme<TokId>>, LexError> {
-        let mut i = 0; // byte index into s
-        let mut lxs = Vec::new(); // lexemes
+    /// Return a lexer for the UTF-8 encoded bytes `b`, or `Err` if `b` is not valid UTF-8. The
+    /// input is validated but not copied, so this is suitable for large inputs such as
+    /// memory-mapped files: e.g. given `let m = unsafe { memmap2::Mmap::map(&file)? };`, then
+    /// `lexerdef.lexer_from_bytes(&m)` lexes the mapped region in place. Since the returned lexer
+    /// borrows `b`, the borrow checker ensures that the underlying buffer (e.g. the mapping)
+    /// outlives the lexer. `Lexeme`s themselves store only offsets, so they can outlive both.
+    pub fn lexer_from_bytes<'a>(&'a self, b: &'a [u8]) -> Result<Lexer<'a, TokId>, Utf8Error> {
+        Ok(Lexer::new(self, str::from_utf8(b)?))
+    }
 
-        while i < self.s.len() {
-            let mut longest = 0; // Length of the longest match
-            let mut longest_ridx = 0; // This is only valid iff longest != 0
-            for (ridx, r) in self.lexerdef.iter_rules().enumerate() {
-                if let Some(m) = r.re.find(&self.s[i..]) {
-                    let len = m.end();
-                    // Note that by using ">", we implicitly prefer an earlier over a later rule, if
-                    // both match an input of the same length.
-                    if len > longest {
-                        longest = len;
-                        longest_ridx = ridx;
-                    }
-                }
+    /// Lex only a prefix of `s` of at most `max_bytes` bytes (e.g. to sniff the format of a file
+    /// from its first few lines). Lexing stops before the first lexeme (or lexing error) which
+    /// extends beyond `max_bytes`, so the prefix's lexemes are exactly those at the start of
+    /// what [`Lexer::lexemes`](struct.Lexer.html#method.lexemes) would produce for the whole of
+    /// `s`. Returns the prefix's lexemes and `true` if there is input after the prefix, or the
+    /// first lexing error within the prefix. Each match is first tried against only the prefix
+    /// and the character following it: if that match already extends beyond the prefix (e.g. a
+    /// very long run of digits), lexing stops without scanning the rest of it.
+    pub fn lex_prefix(&self, s: &str, max_bytes: usize)
+                   -> Result<(Vec<Lexeme<TokId>>, bool), LexError<TokId>>
+    {
+        let full = self.lexer(s);
+        let mut windowed = self.lexer(s);
+        let mut end = max_bytes.saturating_add(1).min(s.len());
+        while !s.is_char_boundary(end) {
+            end += 1;
+        }
+        windowed.end = end;
+        let mut st = full.start_state();
+        let mut lxs = Vec::new();
+        let more = loop {
+            // The windowed match can only be cut short by the window, so if it extends beyond the
+            // prefix then so does the full match. Otherwise, a rule might only match (or match
+            // more) given input beyond the window, so only the full match is conclusive.
+            let mut wst = st.clone();
+            if windowed.step(&mut wst).is_some() && wst.pos > max_bytes {
+                break true;
             }
-            if longest > 0 {
-                self.newlines.borrow_mut().extend(self.s[i..i + longest]
-                                                      .chars()
-                                                      .enumerate()
-                                                      .filter(|&(_, c)| c == '\n')
-                                                      .map(|(j, _)| i + j + 1));
-                let r = &self.lexerdef.get_rule(longest_ridx).unwrap();
-                if r.name.is_some() {
-                    match r.tok_id {
-                        Some(tok_id) => lxs.push(Lexeme::new(tok_id, i, longest)),
-                        None => return Err(LexError{idx: i})
-                    }
-                }
-                i += longest;
-            } else {
-                return Err(LexError{idx: i});
+            match full.step(&mut st) {
+                None => break false,
+                Some(_) if st.pos > max_bytes => break true,
+                Some(None) => (),
+                Some(Some(r)) => lxs.push(r?)
+            }
+        };
+        Ok((lxs, more))
+    }
+
+    /// Lex `s` in recovery mode (see [`Lexer::lex_all`](struct.Lexer.html#method.lex_all)),
+    /// returning only the `(start, len)` byte spans of its errors, in input order. Since valid
+    /// lexemes are discarded as they are encountered, this is cheaper than `lex_all` when only
+    /// the location of any problems is of interest.
+    pub fn error_spans(&self, s: &str) -> Vec<(usize, usize)> {
+        let lexer = self.lexer(s);
+        let mut out = LexAllOutput{lexemes: Vec::new(),
+                                   errors: Vec::new(),
+                                   errors_truncated: false};
+        let mut st = lexer.start_state();
+        let mut mergeable = false;
+        while let Some(r) = lexer.next_from(&mut st) {
+            match r {
+                Ok(_) => mergeable = false,
+                Err(e) => mergeable = lexer.add_error(&mut out, e, mergeable)
             }
         }
-        Ok(lxs)
+        out.errors.iter().map(|e| (e.idx, e.len)).collect()
     }
+}
 
-    /// Return the line and column number of a `Lexeme`, or `Err` if it is clearly out of bounds
-    /// for this lexer.
-    pub fn line_and_col(&self, l: &Lexeme<TokId>) -> Result<(usize, usize), ()> {
-        if l.start > self.s.len() {
-            return Err(());
+impl<TokId: Copy + Eq + TryFrom<usize>> LexerDef<TokId> {
+    /// Create a `LexerDef` with one rule for each `(name, literal)` pair in `terminals` (e.g. the
+    /// terminals declared by a parser's grammar), which matches `literal` exactly (i.e. any regex
+    /// metacharacters in `literal` are escaped). Rules are given IDs in the order the terminals
+    /// are listed, starting from 0. Note that the resulting `LexerDef` only has rules for the
+    /// terminals: any other input (including whitespace) is a lexing error. If two terminals have
+    /// the same name, a `LexBuildError` of kind `DuplicateName` (with no meaningful line or
+    /// column) is returned. Panics if a rule's ID does not fit in `TokId`.
+    pub fn from_terminals(terminals: &[(&str, &str)]) -> LexBuildResult<LexerDef<TokId>> {
+        let mut rules: Vec<Rule<TokId>> = Vec::with_capacity(terminals.len());
+        for (i, &(name, lit)) in terminals.iter().enumerate() {
+            if rules.iter().any(|r| r.name.as_deref() == Some(name)) {
+                return Err(LexBuildError{kind: LexErrorKind::DuplicateName, line: 0, col: 0});
+            }
+            let tok_id = TokId::try_from(i)
+                               .unwrap_or_else(|_| panic!("TokId::try_from failed on {}", i));
+            rules.push(Rule::new(Some(tok_id), Some(name.to_string()), regex::escape(lit))
+                            .unwrap());
         }
+        Ok(LexerDef::new(rules))
+    }
+}
 
-        let newlines = self.newlines.borrow();
-        if newlines.len() == 0 || l.start < newlines[0] {
-            return Ok((1, l.start + 1));
+impl<TokId: Copy + Eq + Hash> LexerDef<TokId> {
+    /// Return a fingerprint of this `LexerDef` suitable for use as e.g. a cache key. The
+    /// fingerprint is computed from each rule's ID, name, regex, `%category`, `%error` message,
+    /// escape style, modes, group, and maximum length, whether its regex is multi-line, whether
+    /// its matches are unbounded (and whether it has continuation, reject, previous-token, or
+    /// count predicates, or balanced delimiters, though not which), in rule order: since rule
+    /// order affects lexing, two `LexerDef`s with the same rules in a different order have
+    /// different fingerprints. The fingerprint is stable across runs and Rust versions on a given
+    /// platform, but may differ between platforms (e.g. of different endianness).
+    pub fn fingerprint(&self) -> u64 {
+        let mut h = FnvHasher::new();
+        self.rules.len().hash(&mut h);
+        for r in &self.rules {
+            r.tok_id.hash(&mut h);
+            r.name.hash(&mut h);
+            r.re_str.hash(&mut h);
+            r.category.hash(&mut h);
+            r.error.hash(&mut h);
+            r.cont.is_some().hash(&mut h);
+            r.escapes.hash(&mut h);
+            r.modes.hash(&mut h);
+            r.set_mode.hash(&mut h);
+            r.reject.is_some().hash(&mut h);
+            r.max_len.hash(&mut h);
+            r.after.is_some().hash(&mut h);
+            r.id_for_count.is_some().hash(&mut h);
+            r.group.hash(&mut h);
+            r.unbounded_match.hash(&mut h);
+            r.balanced.is_some().hash(&mut h);
+            r.multi_line.hash(&mut h);
         }
+        h.finish()
+    }
+}
 
-        for i in 0..newlines.len() - 1 {
-            if newlines[i + 1] > l.start {
-                return Ok((i + 2, l.start - newlines[i] + 1));
-            }
+/// A 64-bit FNV-1a hasher. Unlike `std`'s `DefaultHasher`, its output is guaranteed not to change
+/// between Rust versions.
+pub(crate) struct FnvHasher(u64);
+
+impl FnvHasher {
+    pub(crate) fn new() -> FnvHasher {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
         }
-        Ok((newlines.len() + 1, l.start - newlines[newlines.len() - 1] + 1))
     }
 }
 
-/// A lexeme has a starting position in a string, a length, and a token id. It is a deliberately
-/// small data-structure to large input files to be stored efficiently.
+/// The policy a `Lexer` uses to decide which rule matches at a given point in the input. In all
+/// cases, each rule's regex is matched as normal (i.e. greedily, and only rules which match a
+/// non-empty string are considered); the policy only determines which of the matching rules wins.
+/// `%rest` rules are only considered if no other rule matches, whatever the policy.
 #[derive(Clone, Copy, Debug, PartialEq)]
-pub struct Lexeme<TokId: Copy> {
-    start: usize,
-    len: u32,
-    tok_id: TokId
+pub enum MatchPolicy {
+    /// The rule which matches the longest string wins ("maximal munch"). If several rules match
+    /// strings of the same length, the earliest rule wins.
+    LongestMatch,
+    /// The earliest rule which matches wins, regardless of the length of other rules' matches.
+    FirstMatch,
+    /// The rule which matches the shortest string wins. If several rules match strings of the
+    /// same length, the earliest rule wins.
+    ShortestMatch
 }
 
-impl<TokId: Copy> Lexeme<TokId> {
-    pub fn new(tok_id: TokId, start: usize, len: usize) -> Lexeme<TokId> {
-        Lexeme{
-            start,
-            len: u32::try_from(len).unwrap(),
-            tok_id
-        }
+/// How a `Lexer` treats NUL (`\0`) characters in its input, which often indicate that the input
+/// is binary or truncated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NulPolicy {
+    /// NUL characters are matched by rules like any other character.
+    Match,
+    /// The input is treated as ending just before the first NUL character: the remainder of the
+    /// input is not lexed (though it is still part of the lexer's input, e.g. for
+    /// [`Lexer::line_and_col`](struct.Lexer.html#method.line_and_col)).
+    Eof,
+    /// No lexeme can contain a NUL character: each NUL character results in a `LexError` of kind
+    /// [`Nul`](enum.LexErrorCause.html#variant.Nul) of length 1, and matches before it stop short
+    /// of it.
+    Error
+}
+
+/// The state a `Lexer` carries from one lexeme to the next.
+#[derive(Clone, Debug)]
+struct LexState<TokId> {
+    /// Byte offset into the input.
+    pos: usize,
+    /// The current mode (see `Rule::modes`).
+    mode: usize,
+    /// The ID of the previous lexeme, if any (see `Rule::after`).
+    prev: Option<TokId>,
+    /// The number of matching steps taken (used to decide when to check the deadline).
+    steps: usize,
+    /// The number of lexemes produced (used to enforce `Lexer::max_lexemes`).
+    lexemes: usize,
+    /// The mode in which the most recently fired rule matched.
+    matched_mode: usize,
+    /// The index of the most recently fired rule. Since a rule's `id_for_count` can give a lexeme
+    /// an ID which no rule has, this is the only reliable way of finding a lexeme's rule.
+    matched_rule: usize,
+    /// `(rule index, count)` pairs recording how many lexemes each rule with an `id_for_count`
+    /// has produced. This is empty for lexers with no such rules, so is cheap to clone.
+    counts: Vec<(usize, usize)>,
+    /// `(group, rule index)` pairs recording, for each group (see `Rule::group`) which has had a
+    /// rule match, which rule that was. As with `counts`, this is usually empty.
+    groups: Vec<(usize, usize)>,
+    /// Has the end of the input been processed (see `Rule::is_eof`)?
+    eof_done: bool
+}
+
+impl<TokId> LexState<TokId> {
+    /// The state from which lexing starts, in mode `mode`.
+    fn new(mode: usize) -> Self {
+        LexState{pos: 0, mode, prev: None, steps: 0, lexemes: 0, matched_mode: 0,
+                 matched_rule: 0, counts: Vec::new(), groups: Vec::new(), eof_done: false}
     }
+}
 
-    /// The token ID.
-    pub fn tok_id(&self) -> TokId {
-        self.tok_id
+/// A snapshot of the position and mode of a `Lexer`'s iterator (see
+/// [`Lexer::checkpoint`](struct.Lexer.html#method.checkpoint)). Checkpoints are small and cheap
+/// to clone.
+#[derive(Clone, Debug)]
+pub struct LexerCheckpoint<TokId> {
+    state: LexState<TokId>
+}
+
+impl<TokId> LexerCheckpoint<TokId> {
+    /// The byte offset the lexer's iterator had reached when this checkpoint was taken.
+    pub fn pos(&self) -> usize {
+        self.state.pos
     }
+}
 
-    /// Byte offset of the start of the lexeme
-    pub fn start(&self) -> usize {
-        self.start
+/// A lexer holds a reference to a string and can lex it into `Lexeme`s. Although the struct is
+/// tied to a single string, no guarantees are made about whether the lexemes are cached or not.
+pub struct Lexer<'a, TokId: 'a> {
+    lexerdef: &'a LexerDef<TokId>,
+    s: &'a str,
+    newlines: Rc<RefCell<Vec<usize>>>,
+    /// The byte offset up to which `newlines` has been computed.
+    newlines_scanned: Cell<usize>,
+    max_errors: Option<usize>,
+    merge_across_skips: bool,
+    deadline: Option<Instant>,
+    max_lexemes: Option<usize>,
+    /// The maximum number of bytes a match can extend over, if any.
+    match_window: Option<usize>,
+    /// Are U+2028 and U+2029 line terminators?
+    unicode_line_seps: bool,
+    nul_policy: NulPolicy,
+    /// The offset at which lexing stops: the length of the input, unless the lexer's `NulPolicy`
+    /// is `Eof` and the input contains a NUL character.
+    end: usize,
+    /// A cache `(i, j)` of the offset `j` of the first NUL character at or after offset `i` (or
+    /// the length of the input if there is none), used by `NulPolicy::Error`.
+    next_nul: Cell<Option<(usize, usize)>>,
+    policy: MatchPolicy,
+    initial_mode: usize,
+    /// The state of this lexer's iterator.
+    state: LexState<TokId>
+}
+
+impl<'a, TokId: Copy + Eq> Lexer<'a, TokId> {
+    fn new(lexerdef: &'a LexerDef<TokId>, s: &'a str) -> Lexer<'a, TokId> {
+        Lexer {lexerdef,
+               s,
+               newlines: Rc::new(RefCell::new(Vec::new())),
+               newlines_scanned: Cell::new(0),
+               max_errors: None,
+               merge_across_skips: false,
+               deadline: None,
+               max_lexemes: None,
+               unicode_line_seps: false,
+               nul_policy: NulPolicy::Match,
+               end: s.len(),
+               next_nul: Cell::new(None),
+               match_window: None,
+               policy: MatchPolicy::LongestMatch,
+               initial_mode: 0,
+               state: LexState::new(0)}
     }
 
-    /// Length in bytes of the lexeme
-    pub fn len(&self) -> usize {
-        self.len as usize
+    /// Set the mode the lexer starts in (see [`Rule::modes`](struct.Rule.html#structfield.modes)).
+    /// Defaults to 0.
+    pub fn initial_mode(mut self, mode: usize) -> Self {
+        self.initial_mode = mode;
+        self.state.mode = mode;
+        self
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.len == 0
+    /// The mode the lexer's iterator is currently in.
+    pub fn mode(&self) -> usize {
+        self.state.mode
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::collections::HashMap;
-    use super::*;
-    use parser::parse_lex;
+    /// Set the policy used to choose which rule matches at each point in the input. Defaults to
+    /// [`MatchPolicy::LongestMatch`](enum.MatchPolicy.html#variant.LongestMatch).
+    pub fn match_policy(mut self, policy: MatchPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
 
-    #[test]
-    fn test_basic() {
-        let src = r"
-%%
-[0-9]+ 'int'
-[a-zA-Z]+ 'id'
-[ \t] ;
-        ".to_string();
-        let mut lexer = parse_lex(&src).unwrap();
-        let mut map = HashMap::new();
-        map.insert("int", 0);
-        map.insert("id", 1);
-        assert_eq!(lexer.set_rule_ids(&map), (None, None));
+    /// Record at most `n` errors when lexing in recovery mode. Any subsequent errors are skipped
+    /// silently (lexing still continues to the end of the input), and
+    /// [`LexAllOutput::errors_truncated`](struct.LexAllOutput.html) is set to `true`. By default
+    /// the number of recorded errors is unbounded.
+    pub fn max_errors(mut self, n: usize) -> Self {
+        self.max_errors = Some(n);
+        self
+    }
 
-        let lexemes = lexer.lexer(&"abc 123").lexemes().unwrap();
-        assert_eq!(lexemes.len(), 2);
-        let lex1 = lexemes[0];
-        assert_eq!(lex1.tok_id, 1);
-        assert_eq!(lex1.start, 0);
-        assert_eq!(lex1.len, 3);
-        let lex2 = lexemes[1];
-        assert_eq!(lex2.tok_id, 0);
-        assert_eq!(lex2.start, 4);
-        assert_eq!(lex2.len, 3);
+    /// If `true`, then when lexing in recovery mode, two unmatched regions of input separated
+    /// only by input matched by unnamed rules (e.g. whitespace) are reported as a single
+    /// `LexError` covering both regions and the skipped input between them. If `false` (the
+    /// default), only directly adjacent unmatched regions are merged.
+    pub fn merge_errors_across_skips(mut self, merge: bool) -> Self {
+        self.merge_across_skips = merge;
+        self
     }
 
-    #[test]
-    fn test_basic_error() {
-        let src = "
-%%
-[0-9]+ 'int'
-        ".to_string();
-        let lexer = parse_lex::<u8>(&src).unwrap();
-        match lexer.lexer(&"abc").lexemes() {
-            Ok(_)  => panic!("Invalid input lexed"),
-            Err(LexError{idx: 0}) => (),
-            Err(e) => panic!("Incorrect error returned {:?}", e)
-        };
+    /// Abandon lexing if it is still in progress at `deadline`: lexing then stops with a
+    /// `LexError` of kind [`Timeout`](enum.LexErrorCause.html#variant.Timeout), after which no
+    /// further lexemes are produced. To keep the cost low, the clock is only checked
+    /// periodically, so lexing may continue for a short while after `deadline`. By default there
+    /// is no deadline.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
     }
 
-    #[test]
-    fn test_longest_match() {
-        let src = "%%
-if 'IF'
-[a-z]+ 'ID'
-[ ] ;".to_string();
-        let mut lexer = parse_lex(&src).unwrap();
-        let mut map = HashMap::new();
-        map.insert("IF", 0);
-        map.insert("ID", 1);
-        assert_eq!(lexer.set_rule_ids(&map), (None, None));
+    /// Produce at most `n` lexemes: if the input contains more, lexing stops at the start of the
+    /// first lexeme beyond the limit with a `LexError` of kind
+    /// [`TooManyTokens`](enum.LexErrorCause.html#variant.TooManyTokens), after which no further
+    /// lexemes are produced. Input matched by unnamed rules, and errors, do not count towards
+    /// the limit. By default the number of lexemes is unbounded.
+    pub fn max_lexemes(mut self, n: usize) -> Self {
+        self.max_lexemes = Some(n);
+        self
+    }
 
-        let lexemes = lexer.lexer(&"iff if").lexemes().unwrap();
-        assert_eq!(lexemes.len(), 2);
-        let lex1 = lexemes[0];
-        assert_eq!(lex1.tok_id, 1);
-        assert_eq!(lex1.start, 0);
-        assert_eq!(lex1.len, 3);
-        let lex2 = lexemes[1];
-        assert_eq!(lex2.tok_id, 0);
-        assert_eq!(lex2.start, 4);
-        assert_eq!(lex2.len, 2);
+    /// Limit matches to at most `n` bytes of input (rounded up to a character boundary), except for
+    /// rules whose [`unbounded_match`](struct.Rule.html#structfield.unbounded_match) is `true`:
+    /// each other rule's regex (and continuation predicate) is only given the next `n` bytes of
+    /// input, as if the input ended there. This caps the cost of pathological inputs for rules
+    /// with unbounded repetition (e.g. a megabyte-long run of digits), at the price of splitting
+    /// genuinely longer tokens into several lexemes. `%rest` rules are not affected. By default,
+    /// matches are unbounded.
+    pub fn match_window(mut self, n: usize) -> Self {
+        self.match_window = Some(n);
+        self
     }
 
-    #[test]
-    fn test_line_and_col() {
-        let src = "%%
-[a-z]+ 'ID'
-[ \\n] ;".to_string();
-        let mut lexerdef = parse_lex(&src).unwrap();
-        let mut map = HashMap::new();
-        map.insert("ID", 0);
-        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+    /// Set how NUL characters in the input are treated. Defaults to
+    /// [`NulPolicy::Match`](enum.NulPolicy.html#variant.Match).
+    pub fn nul_policy(mut self, policy: NulPolicy) -> Self {
+        self.nul_policy = policy;
+        self.end = match policy {
+            NulPolicy::Eof => self.s.find('\0').unwrap_or(self.s.len()),
+            _ => self.s.len()
+        };
+        self
+    }
+
+    /// Return the offset of the first NUL character at or after offset `i`, or the length of the
+    /// input if there is none.
+    fn next_nul(&self, i: usize) -> usize {
+        if let Some((from, j)) = self.next_nul.get() {
+            if from <= i && i <= j {
+                return j;
+            }
+        }
+        let j = self.s[i..].find('\0').map_or(self.s.len(), |j| i + j);
+        self.next_nul.set(Some((i, j)));
+        j
+    }
+
+    /// If `true`, the Unicode line and paragraph separators (U+2028 and U+2029) are treated as
+    /// line terminators, as well as `\n`, when computing line and column numbers (as some
+    /// languages, such as JavaScript, require). Note that this does not affect how rules' regexes
+    /// match: in particular, `^` and `$` only match at `\n`. Defaults to `false`.
+    pub fn unicode_line_separators(mut self, unicode_line_seps: bool) -> Self {
+        self.unicode_line_seps = unicode_line_seps;
+        self
+    }
+
+    /// Is `c` a line terminator for the purposes of line numbering?
+    fn is_line_terminator(&self, c: char) -> bool {
+        c == '\n' || (self.unicode_line_seps && (c == '\u{2028}' || c == '\u{2029}'))
+    }
+
+    /// Return the 1-based line number of the byte offset `i` (which must be on a character
+    /// boundary) and the byte offset at which that line starts. This uses, and if necessary
+    /// extends, the same newline index as [`line_and_col`](#method.line_and_col).
+    fn line_of(&self, i: usize) -> (usize, usize) {
+        self.add_newlines(i, 0);
+        let newlines = self.newlines.borrow();
+        let n = newlines.partition_point(|&j| j <= i);
+        (n + 1, if n == 0 { 0 } else { newlines[n - 1] })
+    }
+
+    /// Return all this lexer's lexemes or a `LexError` if there was a problem when lexing. This
+    /// always lexes the entire input, irrespective of how far the lexer has been iterated.
+    pub fn lexemes(&self) -> Result<Vec<Lexeme<TokId>>, LexError<TokId>> {
+        let mut st = self.start_state();
+        let mut lxs = Vec::new(); // lexemes
+        while let Some(r) = self.next_from(&mut st) {
+            lxs.push(r?);
+        }
+        Ok(lxs)
+    }
+
+    /// As [`lexemes`](#method.lexemes), but only those lexemes for which `pred` returns `true` are
+    /// collected. This avoids allocating space for lexemes which are not wanted.
+    pub fn lex_filter<F>(&self, pred: F) -> Result<Vec<Lexeme<TokId>>, LexError<TokId>>
+                  where F: Fn(&Lexeme<TokId>) -> bool
+    {
+        let mut st = self.start_state();
+        let mut lxs = Vec::new(); // lexemes
+        while let Some(r) = self.next_from(&mut st) {
+            let l = r?;
+            if pred(&l) {
+                lxs.push(l);
+            }
+        }
+        Ok(lxs)
+    }
+
+    /// As [`lexemes`](#method.lexemes), but each lexeme is paired with the mode (see
+    /// [`Rule::modes`](struct.Rule.html#structfield.modes)) the lexer was in when the lexeme
+    /// matched, i.e. before any `set_mode` of the lexeme's own rule took effect.
+    pub fn lex_with_modes(&self) -> Result<Vec<(Lexeme<TokId>, usize)>, LexError<TokId>> {
+        let mut st = self.start_state();
+        let mut lxs = Vec::new();
+        while let Some(r) = self.next_from(&mut st) {
+            lxs.push((r?, st.matched_mode));
+        }
+        Ok(lxs)
+    }
+
+    /// As [`lexemes`](#method.lexemes), but with zero-width markers bracketing the region of the
+    /// input lexed in each mode (see [`Rule::modes`](struct.Rule.html#structfield.modes)), e.g. so
+    /// that a highlighter can find where string or comment regions begin and end. The result
+    /// starts with an `Enter` of the initial mode at offset 0, and ends with a `Leave` of the
+    /// final mode at the end of the input. Whenever a rule's `set_mode` changes the mode (whether
+    /// or not the rule is named), a `Leave` of the old mode and an `Enter` of the new mode are
+    /// added at the offset just after the rule's match (and after its lexeme, if any); a
+    /// `set_mode` to the current mode adds no markers.
+    pub fn lex_with_mode_regions(&self) -> Result<Vec<ModeEvent<TokId>>, LexError<TokId>> {
+        let mut st = self.start_state();
+        let mut evs = vec![ModeEvent::Enter(0, st.mode)];
+        loop {
+            let mode = st.mode;
+            match self.step(&mut st) {
+                Some(Some(r)) => evs.push(ModeEvent::Token(r?)),
+                Some(None) => (),
+                None => break
+            }
+            if st.mode != mode {
+                evs.push(ModeEvent::Leave(st.pos, mode));
+                evs.push(ModeEvent::Enter(st.pos, st.mode));
+            }
+        }
+        evs.push(ModeEvent::Leave(st.pos, st.mode));
+        Ok(evs)
+    }
+
+    /// As [`lexemes`](#method.lexemes), but each lexeme is paired with the text it matched. The
+    /// text is borrowed from the input, not from the lexer, so it outlives the lexer.
+    pub fn lex_with_text(&self) -> Result<Vec<(Lexeme<TokId>, &'a str)>, LexError<TokId>> {
+        let s = self.s;
+        self.lexemes()
+            .map(|lxs| lxs.into_iter()
+                          .map(|l| (l, &s[l.start()..l.start() + l.len()]))
+                          .collect())
+    }
+
+    /// As [`lexemes`](#method.lexemes), but returning a sequence of segments which, in order,
+    /// cover the entire input with no gaps or overlaps: each lexeme is a `Segment::Token`, and each
+    /// maximal run of input between lexemes (i.e. input matched by unnamed rules, such as
+    /// whitespace) is a `Segment::Trivia`. Concatenating the text of every segment thus
+    /// reconstructs the input, which is what source-preserving tools (e.g. formatters) need.
+    pub fn segments(&self) -> Result<Vec<Segment<TokId>>, LexError<TokId>> {
+        let mut segs = Vec::new();
+        let mut prev_end = 0;
+        for l in self.lexemes()? {
+            if l.start() > prev_end {
+                segs.push(Segment::Trivia(prev_end, l.start() - prev_end));
+            }
+            prev_end = l.start() + l.len();
+            segs.push(Segment::Token(l));
+        }
+        if self.end > prev_end {
+            segs.push(Segment::Trivia(prev_end, self.end - prev_end));
+        }
+        Ok(segs)
+    }
+
+    /// As [`lexemes`](#method.lexemes), but with the lexemes grouped into logical lines, in
+    /// input order. Logical lines are separated by newlines which are neither part of a lexeme
+    /// (e.g. a multi-line string) nor escaped by an immediately preceding backslash (i.e. a
+    /// line continuation, `\` followed by `\n` or `\r\n`). A lexeme belongs to the logical line
+    /// in which it starts, even if it spans a continuation. Logical lines without lexemes are
+    /// omitted.
+    pub fn lex_by_logical_line(&self) -> Result<Vec<Vec<Lexeme<TokId>>>, LexError<TokId>> {
+        let mut lines = Vec::new();
+        let mut cur = Vec::new();
+        let mut prev_end = 0;
+        for l in self.lexemes()? {
+            if !cur.is_empty() && self.has_line_break(prev_end, l.start()) {
+                lines.push(cur);
+                cur = Vec::new();
+            }
+            prev_end = l.start() + l.len();
+            cur.push(l);
+        }
+        if !cur.is_empty() {
+            lines.push(cur);
+        }
+        Ok(lines)
+    }
+
+    /// Does the input between byte offsets `start` and `end` contain a newline which is not
+    /// escaped by a line continuation?
+    fn has_line_break(&self, start: usize, end: usize) -> bool {
+        self.s[start..end].char_indices().any(|(j, c)| {
+            if c != '\n' {
+                return false;
+            }
+            let before = &self.s[..start + j];
+            !(before.ends_with('\\') || before.ends_with("\\\r"))
+        })
+    }
+
+    /// As [`lexemes`](#method.lexemes), but each lexeme is paired with its text decoded as per
+    /// [`decode`](#method.decode) if its rule has an escape style, or `None` otherwise. Returns
+    /// a `LexError` if lexing fails or if a lexeme contains an invalid escape.
+    pub fn lexemes_decoded(&self) -> Result<DecodedLexemes<TokId>, LexError<TokId>> {
+        let mut st = self.start_state();
+        let mut out = Vec::new();
+        while let Some(r) = self.next_from(&mut st) {
+            let l = r?;
+            let d = match self.lexerdef.rules[st.matched_rule].escapes {
+                Some(style) => Some(self.decode_as(style, &l)?),
+                None => None
+            };
+            out.push((l, d));
+        }
+        Ok(out)
+    }
+
+    /// Return the text of the lexeme `l` with any escapes decoded according to its rule's
+    /// [`escapes`](struct.Rule.html#structfield.escapes) style. If the rule has no escape style,
+    /// the text is returned verbatim. Note that the entire text of the lexeme is decoded: any
+    /// delimiters (e.g. the quotes around a string literal) are preserved. If the lexeme contains
+    /// an invalid escape, a `LexError` covering that escape is returned. The lexeme's rule is the
+    /// rule with its ID: if no rule has that ID (e.g. because it was returned by a rule's
+    /// [`id_for_count`](struct.Rule.html#structfield.id_for_count)), the text is returned
+    /// verbatim (but see [`lexemes_decoded`](#method.lexemes_decoded), which has no such
+    /// limitation).
+    pub fn decode(&self, l: &Lexeme<TokId>) -> Result<String, LexError<TokId>> {
+        match self.lexerdef.rules.iter().find(|r| r.tok_id == Some(l.tok_id())) {
+            Some(&Rule{escapes: Some(style), ..}) => self.decode_as(style, l),
+            _ => Ok(self.s[l.start()..l.start() + l.len()].to_string())
+        }
+    }
+
+    /// Return the text of the lexeme `l` with any escapes decoded according to `style`.
+    fn decode_as(&self, style: EscapeStyle, l: &Lexeme<TokId>) -> Result<String, LexError<TokId>> {
+        decode(style, &self.s[l.start()..l.start() + l.len()]).map_err(|(off, len)| {
+            LexError{idx: l.start() + off, len, cause: LexErrorCause::InvalidEscape}
+        })
+    }
+
+    /// Lex the entire input in "recovery mode". Rather than stopping at the first error, input
+    /// which no rule matches is skipped a character at a time, and lexing continues from the next
+    /// character; consecutive skipped characters are reported as a single `LexError` (see also
+    /// [`merge_errors_across_skips`](#method.merge_errors_across_skips)). Input which matches a
+    /// rule whose ID is `None`, or which exceeds its rule's maximum length, is also reported as
+    /// an error and skipped.
+    pub fn lex_all(&self) -> LexAllOutput<TokId> {
+        let mut out = LexAllOutput{lexemes: Vec::new(),
+                                   errors: Vec::new(),
+                                   errors_truncated: false};
+        let mut st = self.start_state();
+        let mut mergeable = false;
+        while let Some(r) = self.next_from(&mut st) {
+            match r {
+                Ok(l) => {
+                    out.lexemes.push(l);
+                    mergeable = false;
+                },
+                Err(e) => mergeable = self.add_error(&mut out, e, mergeable)
+            }
+        }
+        out
+    }
+
+    /// The state from which a lexer starts lexing its input.
+    fn start_state(&self) -> LexState<TokId> {
+        LexState::new(self.initial_mode)
+    }
+
+    /// Return the next lexeme, or lexing error, starting from the state `st` (which is updated
+    /// to reflect the input consumed); or `None` if there are no more lexemes. Input which no
+    /// rule matches results in an error covering a single character.
+    fn next_from(&self, st: &mut LexState<TokId>)
+              -> Option<Result<Lexeme<TokId>, LexError<TokId>>>
+    {
+        loop {
+            if let Some(r) = self.step(st)? {
+                return Some(r);
+            }
+        }
+    }
+
+    /// Take a single matching step from the state `st` (which is updated to reflect the input
+    /// consumed), returning `Some(Some(r))` if this produces a lexeme or error `r`, `Some(None)`
+    /// if input (or the end of the input) was matched by an unnamed rule, or `None` if there is
+    /// nothing more to match.
+    fn step(&self, st: &mut LexState<TokId>)
+         -> Option<Option<Result<Lexeme<TokId>, LexError<TokId>>>>
+    {
+        if st.pos < self.end {
+            let i = st.pos;
+            if let Some(deadline) = self.deadline {
+                st.steps += 1;
+                if st.steps % DEADLINE_CHECK_INTERVAL == 1 && Instant::now() >= deadline {
+                    st.pos = self.end;
+                    st.eof_done = true;
+                    return Some(Some(Err(LexError{idx: i,
+                                                  len: 0,
+                                                  cause: LexErrorCause::Timeout})));
+                }
+            }
+            if self.nul_policy == NulPolicy::Error && self.s.as_bytes()[i] == 0 {
+                self.add_newlines(i, 1);
+                st.pos += 1;
+                return Some(Some(Err(LexError{idx: i, len: 1, cause: LexErrorCause::Nul})));
+            }
+            let (ridx, len) = match self.match_at(i, st) {
+                Some((_, len, Some(cause))) => {
+                    self.add_newlines(i, len);
+                    st.pos += len;
+                    return Some(Some(Err(LexError{idx: i, len, cause})));
+                },
+                Some((ridx, len, None)) => (ridx, len),
+                None => {
+                    let len = self.char_len(i);
+                    self.add_newlines(i, len);
+                    st.pos += len;
+                    return Some(Some(Err(LexError{idx: i,
+                                                  len,
+                                                  cause: LexErrorCause::Unmatched})));
+                }
+            };
+            self.add_newlines(i, len);
+            st.pos += len;
+            return Some(self.fire(st, ridx, i, len));
+        }
+        if !st.eof_done {
+            st.eof_done = true;
+            if let Some(ridx) = self.lexerdef.iter_rules()
+                                             .position(|r| r.is_eof() && r.active_in(st)) {
+                return Some(self.fire(st, ridx, self.end, 0));
+            }
+        }
+        None
+    }
+
+    /// Update the state `st` to reflect the rule at index `ridx` having matched `len` bytes of
+    /// input at byte offset `i`, returning the resulting lexeme or error, or `None` if the rule
+    /// is unnamed.
+    fn fire(&self, st: &mut LexState<TokId>, ridx: usize, i: usize, len: usize)
+         -> Option<Result<Lexeme<TokId>, LexError<TokId>>>
+    {
+        let r = &self.lexerdef.get_rule(ridx).unwrap();
+        st.matched_mode = st.mode;
+        st.matched_rule = ridx;
+        if let Some(g) = r.group {
+            if st.groups.iter().all(|&(x, _)| x != g) {
+                st.groups.push((g, ridx));
+            }
+        }
+        if let Some(mode) = r.set_mode {
+            st.mode = mode;
+        }
+        if r.error.is_some() {
+            return Some(Err(LexError{idx: i, len, cause: LexErrorCause::Rule{ridx}}));
+        }
+        match r.tok_id {
+            _ if r.name.is_none() => None,
+            Some(id) if r.max_len.is_some_and(|m| len > m) => {
+                Some(Err(LexError{idx: i, len, cause: LexErrorCause::TooLong{id}}))
+            },
+            Some(_) if self.max_lexemes.is_some_and(|m| st.lexemes >= m) => {
+                st.pos = self.end;
+                st.eof_done = true;
+                Some(Err(LexError{idx: i, len: 0, cause: LexErrorCause::TooManyTokens}))
+            },
+            Some(mut tok_id) => {
+                if let Some(f) = r.id_for_count {
+                    let n = match st.counts.iter().position(|&(x, _)| x == ridx) {
+                        Some(j) => {
+                            st.counts[j].1 += 1;
+                            st.counts[j].1 - 1
+                        },
+                        None => {
+                            st.counts.push((ridx, 1));
+                            0
+                        }
+                    };
+                    tok_id = f(tok_id, n);
+                }
+                st.lexemes += 1;
+                st.prev = Some(tok_id);
+                Some(Ok(Lexeme::new(tok_id, i, len)))
+            },
+            None => Some(Err(LexError{idx: i, len, cause: LexErrorCause::Unmatched}))
+        }
+    }
+
+    /// Return the tuple `(rule index, match length)` of the rule which would match at byte offset
+    /// `offset` under this lexer's [`MatchPolicy`](enum.MatchPolicy.html) (so, by default, the
+    /// longest match) given the lexer iterator's current mode and previous lexeme, or `None` if no
+    /// rule matches. At the end of the input, the active `%eof` rule (if any) matches with length
+    /// 0 (see [`Rule::is_eof`](struct.Rule.html#method.is_eof)). This neither produces a lexeme
+    /// nor advances the lexer; note that `offset` need not be where the lexer's iterator is.
+    /// Returns `None` if `offset` is out of bounds or not on a character boundary.
+    pub fn longest_match_at(&self, offset: usize) -> Option<(usize, usize)> {
+        if offset > self.end || !self.s.is_char_boundary(offset) {
+            return None;
+        }
+        if offset == self.end {
+            return self.lexerdef.iter_rules()
+                                .position(|r| r.is_eof() && r.active_in(&self.state))
+                                .map(|ridx| (ridx, 0));
+        }
+        self.match_at(offset, &self.state).map(|(ridx, len, _)| (ridx, len))
+    }
+
+    /// Return the next lexeme (or lexing error) without advancing the lexer, or `None` if the end
+    /// of the input has been reached.
+    pub fn peek(&self) -> Option<Result<Lexeme<TokId>, LexError<TokId>>> {
+        self.next_from(&mut self.state.clone())
+    }
+
+    /// Advance the lexer, discarding lexemes and lexing errors, until the next lexeme has the
+    /// ID `tok_id`: that lexeme is then the next returned by the lexer's iterator. Returns the
+    /// `(start, len)` byte span of the input skipped over. If no lexeme with ID `tok_id` is found,
+    /// the rest of the input is skipped.
+    pub fn skip_to(&mut self, tok_id: TokId) -> (usize, usize) {
+        let start = self.state.pos;
+        loop {
+            let mut st = self.state.clone();
+            match self.next_from(&mut st) {
+                Some(Ok(ref l)) if l.tok_id() == tok_id => {
+                    return (start, l.start() - start);
+                },
+                Some(_) => self.state = st,
+                None => {
+                    self.state = st;
+                    return (start, self.end - start);
+                }
+            }
+        }
+    }
+
+    /// Reset this lexer's iterator to the start of the input, in the lexer's initial mode, as if
+    /// no lexemes had been produced (so, in particular, rules'
+    /// [`id_for_count`](struct.Rule.html#structfield.id_for_count) counts restart from 0, and all
+    /// rules disabled by their [`group`](struct.Rule.html#structfield.group) are re-enabled).
+    pub fn reset(&mut self) {
+        self.state = self.start_state();
+    }
+
+    /// Capture the current state of this lexer's iterator (its position, mode, and previous
+    /// lexeme) so that lexing can later be resumed from this point with
+    /// [`restore`](#method.restore), e.g. to backtrack after speculative parsing.
+    pub fn checkpoint(&self) -> LexerCheckpoint<TokId> {
+        LexerCheckpoint{state: self.state.clone()}
+    }
+
+    /// Restore this lexer's iterator to the state captured in `checkpoint`, in constant time:
+    /// the next lexeme returned is then the one which followed the checkpoint. Line and column
+    /// information is unaffected. `checkpoint` must have been taken from this lexer or from
+    /// another lexer over the same input and `LexerDef`: the results are otherwise unspecified.
+    pub fn restore(&mut self, checkpoint: &LexerCheckpoint<TokId>) {
+        self.state = checkpoint.state.clone();
+    }
+
+    /// Record the error `e` in `out`, returning `true` if it was recorded or `false` if it was
+    /// dropped because `out` already holds [`max_errors`](#method.max_errors) errors. If
+    /// `mergeable` is `true` (i.e. the previous error was recorded, and no lexeme has been
+    /// produced since), `e` is merged with the previous error if both are
+    /// [`Unmatched`](enum.LexErrorCause.html#variant.Unmatched) and either adjacent or, if
+    /// merging across skips is enabled, separated only by skipped input.
+    fn add_error(&self, out: &mut LexAllOutput<TokId>, e: LexError<TokId>, mergeable: bool)
+              -> bool
+    {
+        if let Some(last) = out.errors.last_mut().filter(|_| mergeable) {
+            if (last.idx + last.len == e.idx || self.merge_across_skips)
+               && last.cause == LexErrorCause::Unmatched && e.cause == LexErrorCause::Unmatched
+            {
+                last.len = e.idx + e.len - last.idx;
+                return true;
+            }
+        }
+        if self.max_errors.is_some_and(|m| out.errors.len() >= m) {
+            out.errors_truncated = true;
+            false
+        } else {
+            out.errors.push(e);
+            true
+        }
+    }
+
+    /// Return the length in bytes of the character at byte offset `i`.
+    fn char_len(&self, i: usize) -> usize {
+        self.s[i..].chars().next().unwrap().len_utf8()
+    }
+
+    /// Ensure that the positions of all newlines before byte offset `i + len` are recorded. Since
+    /// newlines are recorded contiguously from the start of the input, input which has already
+    /// been scanned (e.g. because it has been lexed before) is not scanned again, and any
+    /// unscanned input before `i` (e.g. skipped by `skip_to`) is scanned too.
+    fn add_newlines(&self, i: usize, len: usize) {
+        let scanned = self.newlines_scanned.get();
+        if i + len <= scanned {
+            return;
+        }
+        self.newlines.borrow_mut().extend(self.s[scanned..i + len]
+                                              .char_indices()
+                                              .filter(|&(_, c)| self.is_line_terminator(c))
+                                              .map(|(j, c)| scanned + j + c.len_utf8()));
+        self.newlines_scanned.set(i + len);
+    }
+
+    /// Return the length of the `%block-indent` match (see
+    /// [`Rule::is_block_indent`](struct.Rule.html#method.is_block_indent)) starting at byte offset
+    /// `i`.
+    fn block_indent_len(&self, i: usize) -> usize {
+        let indent = |line: &str| line.chars().take_while(|&c| c == ' ' || c == '\t').count();
+        let line_end = |start: usize| {
+            self.s[start..self.end].find('\n').map_or(self.end, |j| start + j)
+        };
+        let base = indent(&self.s[self.s[..i].rfind('\n').map_or(0, |j| j + 1)..]);
+        let mut end = line_end(i);
+        let mut pos = end;
+        while pos < self.end {
+            let next_end = line_end(pos + 1);
+            let line = &self.s[pos + 1..next_end];
+            if line.trim().is_empty() {
+                pos = next_end;
+            } else if indent(line) > base {
+                end = next_end;
+                pos = next_end;
+            } else {
+                break;
+            }
+        }
+        if self.s[..end].ends_with('\r') && end > i {
+            end -= 1;
+        }
+        end - i
+    }
+
+    /// Return the tuple `(rule index, match length)` of the rule which, according to this lexer's
+    /// [`MatchPolicy`](enum.MatchPolicy.html), best matches the input starting at byte offset
+    /// `i` in the state `st`, or `None` if no rule matches. The tuple's third element is the
+    /// cause of the error if the rule's delimiters (see `Rule::balanced`) could not be balanced.
+    fn match_at(&self, i: usize, st: &LexState<TokId>)
+             -> Option<(usize, usize, Option<LexErrorCause<TokId>>)>
+    {
+        let mut best = 0; // Length of the best match
+        let mut best_ridx = 0; // This is only valid iff best != 0
+        let mut best_unbalanced = None;
+        let mut window_end = self.match_window.map_or(self.end, |n| i.saturating_add(n))
+                                              .min(self.end);
+        while !self.s.is_char_boundary(window_end) {
+            window_end += 1;
+        }
+        let end = if self.nul_policy == NulPolicy::Error { self.next_nul(i) } else { self.end };
+        window_end = window_end.min(end);
+        for (ridx, r) in self.lexerdef.iter_rules().enumerate() {
+            if r.is_rest() || r.is_eof() || !r.active_in(st) {
+                continue;
+            }
+            if let Some(g) = r.group {
+                if st.groups.iter().any(|&(x, y)| x == g && y != ridx) {
+                    continue;
+                }
+            }
+            let t = if r.unbounded_match { &self.s[i..end] } else { &self.s[i..window_end] };
+            let m = match r.literal {
+                Some(ref lit) if t.starts_with(lit.as_str()) => Some(lit.len()),
+                Some(_) => None,
+                None if r.is_block_indent() => Some(self.block_indent_len(i).min(t.len())),
+                None => r.re.find(t).map(|m| m.end())
+            };
+            if let Some(mut len) = m {
+                if len == 0 {
+                    continue;
+                }
+                let mut unbalanced = None;
+                if let Some(ref b) = r.balanced {
+                    let (end, cause) = b.balance(t, len);
+                    len = end;
+                    unbalanced = cause;
+                }
+                if let Some(cont) = r.cont.filter(|_| unbalanced.is_none()) {
+                    len += t[len..].chars()
+                                   .take_while(|&c| cont(c))
+                                   .map(|c| c.len_utf8())
+                                   .sum::<usize>();
+                }
+                if r.reject.is_some_and(|f| f(&self.s[i..i + len])) {
+                    continue;
+                }
+                // Note that by using strict comparisons, we implicitly prefer an earlier over a
+                // later rule, if both match an input of the same length.
+                match self.policy {
+                    MatchPolicy::LongestMatch => {
+                        if len > best {
+                            best = len;
+                            best_ridx = ridx;
+                            best_unbalanced = unbalanced;
+                        }
+                    },
+                    MatchPolicy::FirstMatch => return Some((ridx, len, unbalanced)),
+                    MatchPolicy::ShortestMatch => {
+                        if best == 0 || len < best {
+                            best = len;
+                            best_ridx = ridx;
+                            best_unbalanced = unbalanced;
+                        }
+                    }
+                }
+            }
+        }
+        if best == 0 {
+            // No normal rule matched, so fall back on the first `%rest` rule (if any).
+            if let Some(ridx) = self.lexerdef.iter_rules()
+                                             .position(|r| r.is_rest() && r.active_in(st)) {
+                return Some((ridx, end - i, None));
+            }
+            return None;
+        }
+        Some((best_ridx, best, best_unbalanced))
+    }
+
+    /// Return the line and column number of a `Lexeme`, or `Err` if it is clearly out of bounds
+    /// for this lexer. Line numbers are computed from an index of newline positions which is
+    /// built incrementally as the input is lexed, so this is cheap for lexemes already produced
+    /// (e.g. by this lexer's iterator). Querying a lexeme beyond the point lexing has reached
+    /// causes the input up to that lexeme to be scanned for newlines.
+    pub fn line_and_col(&self, l: &Lexeme<TokId>) -> Result<(usize, usize), ()> {
+        if l.start > self.s.len() {
+            return Err(());
+        }
+        if self.s.is_char_boundary(l.start) {
+            self.add_newlines(l.start, 0);
+        }
+
+        let newlines = self.newlines.borrow();
+        if newlines.len() == 0 || l.start < newlines[0] {
+            return Ok((1, l.start + 1));
+        }
+
+        for i in 0..newlines.len() - 1 {
+            if newlines[i + 1] > l.start {
+                return Ok((i + 2, l.start - newlines[i] + 1));
+            }
+        }
+        Ok((newlines.len() + 1, l.start - newlines[newlines.len() - 1] + 1))
+    }
+
+    /// Convert the byte offset `byte` into this lexer's input into an offset in Unicode scalar
+    /// values (i.e. `char`s), or `None` if `byte` is out of bounds or not on a character
+    /// boundary.
+    pub fn char_offset(&self, byte: usize) -> Option<usize> {
+        if !self.s.is_char_boundary(byte) {
+            return None;
+        }
+        Some(self.s[..byte].chars().count())
+    }
+
+    /// Convert the byte offset `byte` into this lexer's input into an offset in UTF-16 code units
+    /// (where characters outside the Basic Multilingual Plane count as two units, one for each
+    /// half of their surrogate pair), or `None` if `byte` is out of bounds or not on a character
+    /// boundary.
+    pub fn utf16_offset(&self, byte: usize) -> Option<usize> {
+        if !self.s.is_char_boundary(byte) {
+            return None;
+        }
+        Some(self.s[..byte].chars().map(|c| c.len_utf16()).sum())
+    }
+
+    /// Return the line and column number of the byte offset `byte` into this lexer's input, where
+    /// the column is counted in UTF-16 code units (as used by e.g. the Language Server Protocol),
+    /// or `None` if `byte` is out of bounds or not on a character boundary. As with
+    /// [`line_and_col`](#method.line_and_col), both numbers start from 1.
+    pub fn line_col_utf16(&self, byte: usize) -> Option<(usize, usize)> {
+        if !self.s.is_char_boundary(byte) {
+            return None;
+        }
+        let (line, line_start) = self.line_of(byte);
+        let col = self.s[line_start..byte].chars().map(|c| c.len_utf16()).sum::<usize>() + 1;
+        Some((line, col))
+    }
+
+    /// Return the `proc_macro2::LineColumn`s of the start and end of the span of `len` bytes
+    /// starting at byte offset `start` into this lexer's input (e.g. a lexeme's `start()` and
+    /// `len()`, or a `LexError`'s `idx()` and `len()`), or `None` if either end is out of bounds
+    /// or not on a character boundary. As in proc-macro spans, lines start from 1, columns start
+    /// from 0 and are counted in `char`s, and the end is the position just after the span's last
+    /// character. Only available with the `proc-macro2` feature.
+    #[cfg(feature = "proc-macro2")]
+    pub fn line_columns(&self, start: usize, len: usize) -> Option<(LineColumn, LineColumn)> {
+        let line_column = |byte| {
+            if !self.s.is_char_boundary(byte) {
+                return None;
+            }
+            let (line, line_start) = self.line_of(byte);
+            Some(LineColumn{line, column: self.s[line_start..byte].chars().count()})
+        };
+        let end = start.checked_add(len)?;
+        Some((line_column(start)?, line_column(end)?))
+    }
+}
+
+impl<'a, TokId: Copy + Eq> Lexer<'a, TokId> {
+    /// Lex the input in recovery mode (as this lexer's iterator does), returning the
+    /// `(start, len)` byte span and highlighting category of each lexeme whose rule has a
+    /// `%category`, and of each maximal run of input which could not be lexed, in input order.
+    /// Lexemes whose rule has no category, and input matched by unnamed rules, have no span (i.e.
+    /// are left unhighlighted). This gives a basic syntax highlighter for e.g. editors.
+    pub fn highlight_spans(&self) -> Vec<((usize, usize), Category<'a>)> {
+        let mut spans = Vec::new();
+        let mut st = self.start_state();
+        while let Some(r) = self.next_from(&mut st) {
+            match r {
+                Ok(l) => {
+                    if let Some(ref c) = self.lexerdef.rules[st.matched_rule].category {
+                        spans.push(((l.start(), l.len()), Category::Named(c.as_str())));
+                    }
+                },
+                Err(ref e) if e.len == 0 => (),
+                Err(e) => {
+                    if let Some(&mut ((start, ref mut len), Category::Error)) = spans.last_mut() {
+                        if start + *len == e.idx {
+                            *len += e.len;
+                            continue;
+                        }
+                    }
+                    spans.push(((e.idx, e.len), Category::Error));
+                }
+            }
+        }
+        spans
+    }
+}
+
+impl<'a, TokId: Copy + Eq + Hash> Lexer<'a, TokId> {
+    /// As [`lexemes`](#method.lexemes), but with the lexemes grouped by token ID (e.g. to find
+    /// all the string literals in an input). Within each group, lexemes are in input order.
+    pub fn lex_grouped(&self) -> Result<HashMap<TokId, Vec<Lexeme<TokId>>>, LexError<TokId>> {
+        let mut groups = HashMap::new();
+        for l in self.lexemes()? {
+            groups.entry(l.tok_id()).or_insert_with(Vec::new).push(l);
+        }
+        Ok(groups)
+    }
+}
+
+/// Iterating over a `Lexer` lazily lexes its input, returning each lexeme in turn. Unlike
+/// [`lexemes`](struct.Lexer.html#method.lexemes), iteration does not stop at the first error:
+/// input which no rule matches is returned as a `LexError` covering a single character, and
+/// iteration then continues with the next character.
+impl<'a, TokId: Copy + Eq> Iterator for Lexer<'a, TokId> {
+    type Item = Result<Lexeme<TokId>, LexError<TokId>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // `next_from` borrows `self`, so temporarily move the state out rather than cloning it
+        // (which, for lexers using `Rule::id_for_count` or groups, would allocate).
+        let mut st = mem::replace(&mut self.state, LexState::new(0));
+        let r = self.next_from(&mut st);
+        self.state = st;
+        r
+    }
+}
+
+/// A lexer which owns its input, for APIs which return both a source string and its lexemes.
+/// Since `Lexeme`s store only byte offsets into the input, they do not borrow from the lexer or
+/// the input: they can be stored or returned freely alongside the `OwnedLexer`, with
+/// [`text`](#method.text) resolving them against the owned input when needed. Only the
+/// `LexerDef` is borrowed. The full [`Lexer`](struct.Lexer.html) interface is available through
+/// [`lexer`](#method.lexer), whose results borrow from the `OwnedLexer`.
+pub struct OwnedLexer<'a, TokId: 'a> {
+    lexerdef: &'a LexerDef<TokId>,
+    s: String
+}
+
+impl<'a, TokId: Copy + Eq> OwnedLexer<'a, TokId> {
+    /// Return a (borrowing) lexer for this lexer's input.
+    pub fn lexer<'b>(&'b self) -> Lexer<'b, TokId> {
+        self.lexerdef.lexer(&self.s)
+    }
+
+    /// As [`Lexer::lexemes`](struct.Lexer.html#method.lexemes).
+    pub fn lexemes(&self) -> Result<Vec<Lexeme<TokId>>, LexError<TokId>> {
+        self.lexer().lexemes()
+    }
+
+    /// Return this lexer's input.
+    pub fn src(&self) -> &str {
+        &self.s
+    }
+
+    /// Return the text of `l`, which must have been produced from this lexer's input.
+    pub fn text(&self, l: &Lexeme<TokId>) -> &str {
+        &self.s[l.start()..l.start() + l.len()]
+    }
+
+    /// Consume this lexer, returning its input.
+    pub fn into_src(self) -> String {
+        self.s
+    }
+}
+
+/// A lexer which is fed its input incrementally, one line at a time, as e.g. log processors
+/// receive it. The lexer's mode (see [`Rule::modes`](struct.Rule.html#structfield.modes)) and
+/// previous lexeme are carried over from one line to the next, and lexemes' offsets are relative
+/// to the start of the whole stream, not the current line. Each call to
+/// [`feed_line`](#method.feed_line) lexes the complete lines fed so far: input after the last
+/// newline is buffered until more input arrives, as is a lexeme which reaches the end of the
+/// complete lines (e.g. a run of whitespace), since the next line might extend it. Matches
+/// cannot otherwise look beyond the end of the complete lines fed so far, so constructs which
+/// span lines (e.g. multi-line comments or strings) must be lexed with modes: e.g. a rule
+/// matching `/*` switches to a comment mode, whose rules match the comment's text (which may
+/// then end at a newline) and switch back to the normal mode at `*/`. Lexing errors are reported
+/// as soon as they are found, after which lexing continues. [`finish`](#method.finish) must be
+/// called at the end of the stream to lex any buffered input (including `%eof` rules). The lexer
+/// uses the default settings of [`Lexer`](struct.Lexer.html) (e.g. longest-match and an initial
+/// mode of 0).
+pub struct LineLexer<'a, TokId: 'a> {
+    lexerdef: &'a LexerDef<TokId>,
+    /// Input which has been fed but not yet lexed.
+    buf: String,
+    /// The offset of `buf` within the stream.
+    base: usize,
+    /// The state of the lexer, relative to `buf`.
+    state: LexState<TokId>
+}
+
+impl<'a, TokId: Copy + Eq> LineLexer<'a, TokId> {
+    /// Feed the lexer the next line of input (which should normally include its terminating
+    /// newline), returning the lexemes, and errors (with offsets relative to the start of the
+    /// stream), which can now be completed.
+    pub fn feed_line(&mut self, line: &str) -> Vec<Result<Lexeme<TokId>, LexError<TokId>>> {
+        self.buf.push_str(line);
+        let end = match self.buf.rfind('\n') {
+            Some(j) => j + 1,
+            None => return Vec::new()
+        };
+        let mut lxs = Vec::new();
+        {
+            let mut lexer = Lexer::new(self.lexerdef, &self.buf);
+            lexer.end = end;
+            // `%eof` rules must not fire until the end of the stream.
+            let mut st = LexState{eof_done: true, ..self.state.clone()};
+            loop {
+                let saved = st.clone();
+                match lexer.next_from(&mut st) {
+                    Some(Ok(l)) if st.pos < end => {
+                        lxs.push(Ok(Lexeme::new(l.tok_id(), self.base + l.start(), l.len())));
+                    },
+                    Some(Err(e)) if st.pos < end => {
+                        lxs.push(Err(LexError{idx: self.base + e.idx, ..e}));
+                    },
+                    Some(_) => {
+                        st = saved;
+                        break;
+                    },
+                    None => break
+                }
+            }
+            self.state = LexState{eof_done: false, ..st};
+        }
+        let consumed = self.state.pos;
+        self.buf.drain(..consumed);
+        self.base += consumed;
+        self.state.pos = 0;
+        lxs
+    }
+
+    /// Signal the end of the stream, returning the lexemes for any buffered input, or `Err` for
+    /// the first error (with an offset relative to the start of the stream).
+    pub fn finish(self) -> Result<Vec<Lexeme<TokId>>, LexError<TokId>> {
+        let lexer = Lexer::new(self.lexerdef, &self.buf);
+        let mut st = self.state;
+        let mut lxs = Vec::new();
+        while let Some(r) = lexer.next_from(&mut st) {
+            match r {
+                Ok(l) => lxs.push(Lexeme::new(l.tok_id(), self.base + l.start(), l.len())),
+                Err(e) => return Err(LexError{idx: self.base + e.idx, ..e})
+            }
+        }
+        Ok(lxs)
+    }
+}
+
+/// The highlighting category of a span of input, as returned by
+/// [`Lexer::highlight_spans`](struct.Lexer.html#method.highlight_spans).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category<'a> {
+    /// A lexeme whose rule has this `%category`.
+    Named(&'a str),
+    /// Input which could not be lexed.
+    Error
+}
+
+/// A segment of the input, as returned by [`Lexer::segments`](struct.Lexer.html#method.segments).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Segment<TokId: Copy> {
+    /// The `(start, len)` byte span of input which is not part of any lexeme.
+    Trivia(usize, usize),
+    /// A lexeme.
+    Token(Lexeme<TokId>)
+}
+
+/// An element of the output of
+/// [`Lexer::lex_with_mode_regions`](struct.Lexer.html#method.lex_with_mode_regions).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModeEvent<TokId: Copy> {
+    /// A lexeme.
+    Token(Lexeme<TokId>),
+    /// `Enter(offset, mode)`: the lexer entered `mode` at byte offset `offset`.
+    Enter(usize, usize),
+    /// `Leave(offset, mode)`: the lexer left `mode` at byte offset `offset`.
+    Leave(usize, usize)
+}
+
+/// A lexeme has a starting position in a string, a length, and a token id. It is a deliberately
+/// small data-structure to large input files to be stored efficiently.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lexeme<TokId: Copy> {
+    start: usize,
+    len: u32,
+    tok_id: TokId
+}
+
+impl<TokId: Copy> Lexeme<TokId> {
+    pub fn new(tok_id: TokId, start: usize, len: usize) -> Lexeme<TokId> {
+        Lexeme{
+            start,
+            len: u32::try_from(len).unwrap(),
+            tok_id
+        }
+    }
+
+    /// The token ID.
+    pub fn tok_id(&self) -> TokId {
+        self.tok_id
+    }
+
+    /// Byte offset of the start of the lexeme
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Length in bytes of the lexeme
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// An unbalanced delimiter found by [`check_delimiters`](fn.check_delimiters.html).
+#[derive(Debug, PartialEq)]
+pub enum DelimError<TokId: Copy> {
+    /// The open delimiter (first lexeme) was closed by a closing delimiter (second lexeme) which
+    /// is not its pair.
+    Mismatched(Lexeme<TokId>, Lexeme<TokId>),
+    /// An open delimiter which was never closed.
+    UnmatchedOpen(Lexeme<TokId>),
+    /// A closing delimiter with no corresponding open delimiter.
+    UnmatchedClose(Lexeme<TokId>)
+}
+
+/// Check that the delimiters in `lexemes` are balanced, where `pairs` is a list of `(open token
+/// ID, close token ID)` pairs (e.g. the IDs of `(` and `)`). Lexemes whose IDs do not appear in
+/// `pairs` are ignored. Returns the first unbalanced delimiter found, if any: if all closing
+/// delimiters are matched but some open delimiters are not, the earliest unclosed open delimiter
+/// is returned.
+pub fn check_delimiters<TokId: Copy + Eq>(lexemes: &[Lexeme<TokId>], pairs: &[(TokId, TokId)])
+                                       -> Result<(), DelimError<TokId>>
+{
+    let mut opens = Vec::new();
+    for l in lexemes {
+        if pairs.iter().any(|&(o, _)| o == l.tok_id) {
+            opens.push(*l);
+        } else if pairs.iter().any(|&(_, c)| c == l.tok_id) {
+            match opens.pop() {
+                Some(o) => {
+                    if !pairs.iter().any(|&(po, pc)| po == o.tok_id && pc == l.tok_id) {
+                        return Err(DelimError::Mismatched(o, *l));
+                    }
+                }
+                None => return Err(DelimError::UnmatchedClose(*l))
+            }
+        }
+    }
+    match opens.first() {
+        Some(o) => Err(DelimError::UnmatchedOpen(*o)),
+        None => Ok(())
+    }
+}
+
+/// Do the lexemes `a` (lexed from `a_src`) and `b` (lexed from `b_src`) have the same sequence of
+/// token IDs and texts, once lexemes whose IDs appear in `trivia` (e.g. the IDs of comment
+/// rules) are ignored? Input matched by unnamed rules never produces lexemes, so it is always
+/// ignored. This allows e.g. a cache to determine whether an edit to a file changed any of its
+/// meaningful tokens, regardless of changes to whitespace, comments, or token positions.
+pub fn tokens_equal_ignoring_trivia<TokId: Copy + Eq>(a_src: &str,
+                                                      a: &[Lexeme<TokId>],
+                                                      b_src: &str,
+                                                      b: &[Lexeme<TokId>],
+                                                      trivia: &[TokId])
+                                                   -> bool
+{
+    let mut a_iter = a.iter().filter(|l| !trivia.contains(&l.tok_id));
+    let mut b_iter = b.iter().filter(|l| !trivia.contains(&l.tok_id));
+    loop {
+        match (a_iter.next(), b_iter.next()) {
+            (None, None) => return true,
+            (Some(x), Some(y)) => {
+                if x.tok_id != y.tok_id
+                   || a_src[x.start..x.start + x.len()] != b_src[y.start..y.start + y.len()] {
+                    return false;
+                }
+            },
+            _ => return false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use super::*;
+    use parser::parse_lex;
+
+    #[test]
+    fn test_basic() {
+        let src = r"
+%%
+[0-9]+ 'int'
+[a-zA-Z]+ 'id'
+[ \t] ;
+        ".to_string();
+        let mut lexer = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("int", 0);
+        map.insert("id", 1);
+        assert_eq!(lexer.set_rule_ids(&map), (None, None));
+
+        let lexemes = lexer.lexer(&"abc 123").lexemes().unwrap();
+        assert_eq!(lexemes.len(), 2);
+        let lex1 = lexemes[0];
+        assert_eq!(lex1.tok_id, 1);
+        assert_eq!(lex1.start, 0);
+        assert_eq!(lex1.len, 3);
+        let lex2 = lexemes[1];
+        assert_eq!(lex2.tok_id, 0);
+        assert_eq!(lex2.start, 4);
+        assert_eq!(lex2.len, 3);
+    }
+
+    #[test]
+    fn test_rule_regex() {
+        let src = "%%
+[0-9]+ 'int'
+[a-z]+ 'id'".to_string();
+        let lexerdef = parse_lex::<u8>(&src).unwrap();
+        let re = lexerdef.rule_regex(1).unwrap();
+        assert_eq!(re.find("abc1").unwrap().end(), 3);
+        assert!(re.find("1abc").is_none());
+        assert!(lexerdef.rule_regex(2).is_none());
+    }
+
+    #[test]
+    fn test_basic_error() {
+        let src = "
+%%
+[0-9]+ 'int'
+        ".to_string();
+        let lexer = parse_lex::<u8>(&src).unwrap();
+        match lexer.lexer(&"abc").lexemes() {
+            Ok(_)  => panic!("Invalid input lexed"),
+            Err(LexError{idx: 0, ..}) => (),
+            Err(e) => panic!("Incorrect error returned {:?}", e)
+        };
+    }
+
+    #[test]
+    fn test_longest_match() {
+        let src = "%%
+if 'IF'
+[a-z]+ 'ID'
+[ ] ;".to_string();
+        let mut lexer = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("IF", 0);
+        map.insert("ID", 1);
+        assert_eq!(lexer.set_rule_ids(&map), (None, None));
+
+        let lexemes = lexer.lexer(&"iff if").lexemes().unwrap();
+        assert_eq!(lexemes.len(), 2);
+        let lex1 = lexemes[0];
+        assert_eq!(lex1.tok_id, 1);
+        assert_eq!(lex1.start, 0);
+        assert_eq!(lex1.len, 3);
+        let lex2 = lexemes[1];
+        assert_eq!(lex2.tok_id, 0);
+        assert_eq!(lex2.start, 4);
+        assert_eq!(lex2.len, 2);
+    }
+
+    #[test]
+    fn test_owned_lexer() {
+        let src = "%%
+[a-z]+ 'ID'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        fn lex<'a>(lexerdef: &'a LexerDef<u8>) -> (OwnedLexer<'a, u8>, Vec<Lexeme<u8>>) {
+            let lexer = lexerdef.owned_lexer(format!("{} {}", "ab", "c"));
+            let lexemes = lexer.lexemes().unwrap();
+            (lexer, lexemes)
+        }
+        let (lexer, lexemes) = lex(&lexerdef);
+        assert_eq!(lexemes.iter().map(|l| lexer.text(l)).collect::<Vec<_>>(), vec!["ab", "c"]);
+        assert_eq!(lexer.lexer().line_and_col(&lexemes[1]).unwrap(), (1, 4));
+        assert_eq!(lexer.src(), "ab c");
+        assert_eq!(lexer.into_src(), "ab c");
+    }
+
+    #[test]
+    fn test_line_and_col() {
+        let src = "%%
+[a-z]+ 'ID'
+[ \\n] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
 
         let lexer = lexerdef.lexer("a b c");
         let lexemes = lexer.lexemes().unwrap();
-        assert_eq!(lexemes.len(), 3);
-        assert_eq!(lexer.line_and_col(&lexemes[1]).unwrap(), (1, 3));
+        assert_eq!(lexemes.len(), 3);
+        assert_eq!(lexer.line_and_col(&lexemes[1]).unwrap(), (1, 3));
+
+        let lexer = lexerdef.lexer("a b c\n");
+        let lexemes = lexer.lexemes().unwrap();
+        assert_eq!(lexemes.len(), 3);
+        assert_eq!(lexer.line_and_col(&lexemes[1]).unwrap(), (1, 3));
+
+        let lexer = lexerdef.lexer(" a\nb\n  c d");
+        let lexemes = lexer.lexemes().unwrap();
+        assert_eq!(lexemes.len(), 4);
+        assert_eq!(lexer.line_and_col(&lexemes[0]).unwrap(), (1, 2));
+        assert_eq!(lexer.line_and_col(&lexemes[1]).unwrap(), (2, 1));
+        assert_eq!(lexer.line_and_col(&lexemes[2]).unwrap(), (3, 3));
+        assert_eq!(lexer.line_and_col(&lexemes[3]).unwrap(), (3, 5));
+
+        let mut lexer = lexerdef.lexer("a\nb\nc d");
+        let a = lexer.next().unwrap().unwrap();
+        assert_eq!(lexer.line_and_col(&a).unwrap(), (1, 1));
+        assert_eq!(lexer.line_and_col(&Lexeme::new(0, 6, 1)).unwrap(), (3, 3));
+        let b = lexer.next().unwrap().unwrap();
+        assert_eq!(lexer.line_and_col(&b).unwrap(), (2, 1));
+
+        let fake_lexeme = Lexeme{start: 100, len: 1, tok_id: 0};
+        if let Ok(_) = lexer.line_and_col(&fake_lexeme) {
+            panic!("line_and_col returned Ok(_) when it should have returned Err.");
+        }
+    }
+
+    #[test]
+    fn test_missing_from_lexer_and_parser() {
+        let src = "%%
+[a-z]+ 'ID'
+[ \\n] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("INT", 0);
+        let mut missing_from_lexer = HashSet::new();
+        missing_from_lexer.insert("INT");
+        let mut missing_from_parser = HashSet::new();
+        missing_from_parser.insert("ID");
+        assert_eq!(lexerdef.set_rule_ids(&map), (Some(missing_from_lexer), Some(missing_from_parser)));
+
+        match lexerdef.lexer(&" a ").lexemes() {
+            Ok(_)  => panic!("Invalid input lexed"),
+            Err(LexError{idx: 1, ..}) => (),
+            Err(e) => panic!("Incorrect error returned {:?}", e)
+        };
+    }
+
+    #[test]
+    fn test_rest() {
+        let src = "%%
+[a-z]+ 'ID'
+[ \\n] ;
+%rest 'REST'".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("REST", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        assert!(lexerdef.get_rule_by_name("REST").unwrap().is_rest());
+
+        let lexemes = lexerdef.lexer("a b").lexemes().unwrap();
+        assert_eq!(lexemes.len(), 2);
+        assert!(lexemes.iter().all(|l| l.tok_id() == 0));
+
+        let lexemes = lexerdef.lexer("a b\n1 c\nd").lexemes().unwrap();
+        assert_eq!(lexemes.len(), 3);
+        assert_eq!(lexemes[2], Lexeme::new(1, 4, 5));
+    }
+
+    #[test]
+    fn test_cont() {
+        let src = "%%
+[a-z] 'ID'
+[0-9]+ 'INT'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("INT", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        lexerdef.get_rule_by_name_mut("ID").unwrap().cont = Some(char::is_alphanumeric);
+
+        let lexemes = lexerdef.lexer("ab1é 2").lexemes().unwrap();
+        assert_eq!(lexemes, vec![Lexeme::new(0, 0, 5), Lexeme::new(1, 6, 1)]);
+    }
+
+    #[test]
+    fn test_lex_all() {
+        let src = "%%
+[a-z]+ 'ID'
+[ \\n] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let out = lexerdef.lexer("a 12 b\n3 !c").lex_all();
+        assert_eq!(out.lexemes, vec![Lexeme::new(0, 0, 1), Lexeme::new(0, 5, 1),
+                                     Lexeme::new(0, 10, 1)]);
+        assert_eq!(out.errors.iter().map(|e| (e.idx(), e.len())).collect::<Vec<_>>(),
+                   vec![(2, 2), (7, 1), (9, 1)]);
+        assert!(!out.errors_truncated);
+
+        let out = lexerdef.lexer("a 12 b\n3 !c").max_errors(2).lex_all();
+        assert_eq!(out.lexemes.len(), 3);
+        assert_eq!(out.errors.iter().map(|e| (e.idx(), e.len())).collect::<Vec<_>>(),
+                   vec![(2, 2), (7, 1)]);
+        assert!(out.errors_truncated);
+    }
+
+    #[test]
+    fn test_check_delimiters() {
+        let src = "%%
+\\( 'LP'
+\\) 'RP'
+\\[ 'LS'
+\\] 'RS'
+[a-z]+ 'ID'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("LP", 0);
+        map.insert("RP", 1);
+        map.insert("LS", 2);
+        map.insert("RS", 3);
+        map.insert("ID", 4);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        let pairs = [(0, 1), (2, 3)];
+
+        let lexemes = lexerdef.lexer("(a [b] (c))").lexemes().unwrap();
+        assert_eq!(check_delimiters(&lexemes, &pairs), Ok(()));
+
+        let lexemes = lexerdef.lexer("(a [b) c").lexemes().unwrap();
+        assert_eq!(check_delimiters(&lexemes, &pairs),
+                   Err(DelimError::Mismatched(Lexeme::new(2, 3, 1), Lexeme::new(1, 5, 1))));
+
+        let lexemes = lexerdef.lexer("a) (b").lexemes().unwrap();
+        assert_eq!(check_delimiters(&lexemes, &pairs),
+                   Err(DelimError::UnmatchedClose(Lexeme::new(1, 1, 1))));
+
+        let lexemes = lexerdef.lexer("( [ b ]").lexemes().unwrap();
+        assert_eq!(check_delimiters(&lexemes, &pairs),
+                   Err(DelimError::UnmatchedOpen(Lexeme::new(0, 0, 1))));
+    }
+
+    #[test]
+    fn test_match_policy() {
+        let src = "%%
+if 'IF'
+[a-z]+ 'ID'
+[a-z] 'CHAR'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("IF", 1);
+        map.insert("CHAR", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let ids = |policy| lexerdef.lexer("iff if")
+                                   .match_policy(policy)
+                                   .lexemes()
+                                   .unwrap()
+                                   .iter()
+                                   .map(|l| (l.tok_id(), l.len()))
+                                   .collect::<Vec<_>>();
+        assert_eq!(ids(MatchPolicy::LongestMatch), vec![(0, 3), (1, 2)]);
+        assert_eq!(ids(MatchPolicy::FirstMatch), vec![(1, 2), (0, 1), (1, 2)]);
+        assert_eq!(ids(MatchPolicy::ShortestMatch),
+                   vec![(2, 1), (2, 1), (0, 1), (2, 1), (0, 1)]);
+    }
+
+    #[test]
+    fn test_decode() {
+        let src = r#"%%
+"(\\.|[^"\\])*" 'STR'
+[ ] ;"#.to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("STR", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = r#""a\tb" "\u00e9""#;
+        assert_eq!(lexerdef.lexer(s).lexemes_decoded().unwrap(),
+                   vec![(Lexeme::new(0, 0, 6), None), (Lexeme::new(0, 7, 8), None)]);
+        lexerdef.get_rule_by_name_mut("STR").unwrap().escapes = Some(EscapeStyle::Json);
+        assert_eq!(lexerdef.lexer(s).lexemes_decoded().unwrap(),
+                   vec![(Lexeme::new(0, 0, 6), Some("\"a\tb\"".to_string())),
+                        (Lexeme::new(0, 7, 8), Some("\"\u{e9}\"".to_string()))]);
+        match lexerdef.lexer(r#""a" "b\q""#).lexemes_decoded() {
+            Err(LexError{idx: 6, len: 2, cause: LexErrorCause::InvalidEscape}) => (),
+            r => panic!("Incorrect result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let fp = |src: &str| parse_lex::<u8>(src).unwrap().fingerprint();
+        let src = "%%
+[0-9]+ 'INT'
+[a-z]+ 'ID'";
+        assert_eq!(fp(src), fp(src));
+        assert_ne!(fp(src), fp("%%
+[a-z]+ 'ID'
+[0-9]+ 'INT'"));
+        assert_ne!(fp(src), fp("%%
+[0-9]+ 'INT'
+[a-z]* 'ID'"));
+
+        let mut lexerdef = parse_lex::<u8>(src).unwrap();
+        lexerdef.get_rule_by_name_mut("ID").unwrap().escapes = Some(EscapeStyle::C);
+        assert_ne!(fp(src), lexerdef.fingerprint());
+
+        assert_ne!(fp(src), fp("%%
+%category 'number'
+[0-9]+ 'INT'
+[a-z]+ 'ID'"));
+    }
+
+    #[test]
+    fn test_skip_to() {
+        let src = "%%
+[a-z]+ 'ID'
+; 'SEMI'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("SEMI", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let mut lexer = lexerdef.lexer("a b 1 c; d");
+        assert_eq!(lexer.next().unwrap().unwrap(), Lexeme::new(0, 0, 1));
+        assert_eq!(lexer.skip_to(1), (1, 6));
+        assert_eq!(lexer.peek().unwrap().unwrap(), Lexeme::new(1, 7, 1));
+        assert_eq!(lexer.next().unwrap().unwrap(), Lexeme::new(1, 7, 1));
+        assert_eq!(lexer.skip_to(1), (8, 2));
+        assert!(lexer.next().is_none());
+        assert!(lexer.peek().is_none());
+    }
+
+    #[test]
+    fn test_id_for_count() {
+        let src = "%%
+\\[[a-z]+\\] 'SECTION'
+[a-z]+ 'ID'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("SECTION", 0);
+        map.insert("ID", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        const DUP_SECTION: u8 = 1;
+        fn dup(id: u8, n: usize) -> u8 {
+            if n == 0 { id } else { DUP_SECTION }
+        }
+        lexerdef.get_rule_by_name_mut("SECTION").unwrap().id_for_count = Some(dup);
+
+        let mut lexer = lexerdef.lexer("[a] x [b] [c]");
+        assert_eq!(lexer.lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 3), Lexeme::new(2, 4, 1), Lexeme::new(1, 6, 3),
+                        Lexeme::new(1, 10, 3)]);
+        assert_eq!(lexer.next().unwrap().unwrap(), Lexeme::new(0, 0, 3));
+        let cp = lexer.checkpoint();
+        assert_eq!(lexer.nth(1).unwrap().unwrap(), Lexeme::new(1, 6, 3));
+        lexer.restore(&cp);
+        assert_eq!(lexer.nth(1).unwrap().unwrap(), Lexeme::new(1, 6, 3));
+        lexer.reset();
+        assert_eq!(lexer.next().unwrap().unwrap(), Lexeme::new(0, 0, 3));
+
+        // No rule has the ID `DUP_SECTION`, but its lexemes still have a rule.
+        lexerdef.get_rule_by_name_mut("SECTION").unwrap().category = Some("section".to_string());
+        lexerdef.get_rule_by_name_mut("SECTION").unwrap().escapes = Some(EscapeStyle::C);
+        let lexer = lexerdef.lexer("[a] [b]");
+        assert_eq!(lexer.highlight_spans(),
+                   vec![((0, 3), Category::Named("section")),
+                        ((4, 3), Category::Named("section"))]);
+        assert_eq!(lexer.lexemes_decoded().unwrap(),
+                   vec![(Lexeme::new(0, 0, 3), Some("[a]".to_string())),
+                        (Lexeme::new(1, 4, 3), Some("[b]".to_string()))]);
+        assert_eq!(lexer.decode(&Lexeme::new(1, 4, 3)).unwrap(), "[b]");
+    }
+
+    #[test]
+    fn test_set_multi_line() {
+        let src = "%%
+[a-z]+$ 'EOL'
+[a-z]+ 'ID'
+\\n ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("EOL", 0);
+        map.insert("ID", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "ab\ncd";
+        assert_eq!(lexerdef.lexer(s).lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 2), Lexeme::new(0, 3, 2)]);
+        let fp = lexerdef.fingerprint();
+        lexerdef.set_multi_line(false).unwrap();
+        assert_eq!(lexerdef.lexer(s).lexemes().unwrap(),
+                   vec![Lexeme::new(1, 0, 2), Lexeme::new(0, 3, 2)]);
+        assert_ne!(lexerdef.fingerprint(), fp);
+        lexerdef.set_multi_line(true).unwrap();
+        assert_eq!(lexerdef.lexer(s).lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 2), Lexeme::new(0, 3, 2)]);
+    }
+
+    #[test]
+    fn test_groups() {
+        let src = "%%
+:= 'ASSIGN'
+= 'EQ'
+[a-z]+ 'ID'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ASSIGN", 0);
+        map.insert("EQ", 1);
+        map.insert("ID", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        lexerdef.get_rule_by_name_mut("ASSIGN").unwrap().group = Some(0);
+        lexerdef.get_rule_by_name_mut("EQ").unwrap().group = Some(0);
+
+        let mut lexer = lexerdef.lexer("a := b");
+        assert_eq!(lexer.lexemes().unwrap(),
+                   vec![Lexeme::new(2, 0, 1), Lexeme::new(0, 2, 2), Lexeme::new(2, 5, 1)]);
+        assert_eq!(lexerdef.lexer("a = b := c").lexemes().unwrap_err().idx(), 6);
+        assert_eq!(lexerdef.lexer("a := b = c").lexemes().unwrap_err().idx(), 7);
+        assert_eq!(lexer.nth(1).unwrap().unwrap(), Lexeme::new(0, 2, 2));
+        lexer.reset();
+        assert_eq!(lexer.count(), 3);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let src = "%%
+[a-z]+ 'ID'
+# 'HASH'
+[0-9]+ 'INT'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("HASH", 1);
+        map.insert("INT", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        lexerdef.get_rule_by_name_mut("ID").unwrap().modes = Some(vec![0]);
+        lexerdef.get_rule_by_name_mut("INT").unwrap().modes = Some(vec![1]);
+        lexerdef.get_rule_by_name_mut("HASH").unwrap().set_mode = Some(1);
+
+        let mut lexer = lexerdef.lexer("ab # 12 34");
+        assert_eq!(lexer.next().unwrap().unwrap(), Lexeme::new(0, 0, 2));
+        assert_eq!(lexer.next().unwrap().unwrap(), Lexeme::new(1, 3, 1));
+        let cp = lexer.checkpoint();
+        assert_eq!(cp.pos(), 4);
+        assert_eq!(lexer.by_ref().map(|l| l.unwrap()).collect::<Vec<_>>(),
+                   vec![Lexeme::new(2, 5, 2), Lexeme::new(2, 8, 2)]);
+        lexer.restore(&cp.clone());
+        assert_eq!(lexer.mode(), 1);
+        assert_eq!(lexer.next().unwrap().unwrap(), Lexeme::new(2, 5, 2));
+        assert_eq!(lexer.line_and_col(&Lexeme::new(2, 8, 2)), Ok((1, 9)));
+    }
+
+    #[test]
+    fn test_lex_filter() {
+        let src = "%%
+[a-z]+ 'ID'
+[0-9]+ 'INT'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("INT", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let lexer = lexerdef.lexer("a 1 b 22");
+        assert_eq!(lexer.lex_filter(|l| l.tok_id() == 1).unwrap(),
+                   vec![Lexeme::new(1, 2, 1), Lexeme::new(1, 6, 2)]);
+        assert!(lexerdef.lexer("a !").lex_filter(|_| false).is_err());
+    }
+
+    #[test]
+    fn test_modes() {
+        let src = "%%
+[a-z]+ 'ID'
+# 'HASH'
+[^#]+ 'RAW'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("HASH", 1);
+        map.insert("RAW", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        const NORMAL: usize = 0;
+        const RAW: usize = 1;
+        lexerdef.get_rule_by_name_mut("ID").unwrap().modes = Some(vec![NORMAL]);
+        lexerdef.get_rule_by_name_mut("RAW").unwrap().modes = Some(vec![RAW]);
+        lexerdef.get_rule_by_name_mut("HASH").unwrap().set_mode = Some(RAW);
+
+        let lexemes = lexerdef.lexer("ab #c d").lexemes().unwrap();
+        assert_eq!(lexemes, vec![Lexeme::new(0, 0, 2), Lexeme::new(1, 3, 1),
+                                 Lexeme::new(2, 4, 3)]);
+        let lexemes = lexerdef.lexer("ab c").initial_mode(RAW).lexemes().unwrap();
+        assert_eq!(lexemes, vec![Lexeme::new(2, 0, 4)]);
+
+        assert_eq!(lexerdef.lexer("ab #c d").lex_with_modes().unwrap(),
+                   vec![(Lexeme::new(0, 0, 2), NORMAL), (Lexeme::new(1, 3, 1), NORMAL),
+                        (Lexeme::new(2, 4, 3), RAW)]);
+
+        let mut lexer = lexerdef.lexer("ab #c");
+        assert_eq!(lexer.mode(), NORMAL);
+        lexer.next();
+        lexer.next();
+        assert_eq!(lexer.mode(), RAW);
+    }
+
+    #[test]
+    fn test_lex_with_mode_regions() {
+        let src = "%%
+[a-z]+ 'ID'
+/\\* ;
+\\*/ ;
+[^*]+|\\* ;
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        const NORMAL: usize = 0;
+        const COMMENT: usize = 1;
+        lexerdef.rules[0].modes = Some(vec![NORMAL]);
+        lexerdef.rules[1].modes = Some(vec![NORMAL]);
+        lexerdef.rules[1].set_mode = Some(COMMENT);
+        lexerdef.rules[2].modes = Some(vec![COMMENT]);
+        lexerdef.rules[2].set_mode = Some(NORMAL);
+        lexerdef.rules[3].modes = Some(vec![COMMENT]);
+
+        assert_eq!(lexerdef.lexer("a /* b */ c").lex_with_mode_regions().unwrap(),
+                   vec![ModeEvent::Enter(0, NORMAL),
+                        ModeEvent::Token(Lexeme::new(0, 0, 1)),
+                        ModeEvent::Leave(4, NORMAL),
+                        ModeEvent::Enter(4, COMMENT),
+                        ModeEvent::Leave(9, COMMENT),
+                        ModeEvent::Enter(9, NORMAL),
+                        ModeEvent::Token(Lexeme::new(0, 10, 1)),
+                        ModeEvent::Leave(11, NORMAL)]);
+        assert_eq!(lexerdef.lexer("/* a").lex_with_mode_regions().unwrap(),
+                   vec![ModeEvent::Enter(0, NORMAL),
+                        ModeEvent::Leave(2, NORMAL),
+                        ModeEvent::Enter(2, COMMENT),
+                        ModeEvent::Leave(4, COMMENT)]);
+        assert!(lexerdef.lexer("a !").lex_with_mode_regions().is_err());
+    }
+
+    #[test]
+    fn test_line_lexer() {
+        let src = "%%
+[a-z]+ 'ID'
+\"[^\"]*\" 'STR'
+%eof 'EOF'
+[ \\n] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("STR", 1);
+        map.insert("EOF", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let feed = |lexer: &mut LineLexer<u8>, line| {
+            lexer.feed_line(line).into_iter().map(|r| r.map_err(|e| e.idx())).collect::<Vec<_>>()
+        };
+        let mut lexer = lexerdef.line_lexer();
+        assert_eq!(feed(&mut lexer, "ab c\n"), vec![Ok(Lexeme::new(0, 0, 2)),
+                                                    Ok(Lexeme::new(0, 3, 1))]);
+        assert_eq!(feed(&mut lexer, "d \"e\" f"), vec![]);
+        assert_eq!(feed(&mut lexer, "g\nh"), vec![Ok(Lexeme::new(0, 5, 1)),
+                                                  Ok(Lexeme::new(1, 7, 3)),
+                                                  Ok(Lexeme::new(0, 11, 2))]);
+        assert_eq!(lexer.finish().unwrap(), vec![Lexeme::new(0, 14, 1), Lexeme::new(2, 15, 0)]);
+
+        // Errors are reported straight away, and don't stop later lines from being lexed.
+        let mut lexer = lexerdef.line_lexer();
+        assert_eq!(feed(&mut lexer, "ab \"c\n"), vec![Ok(Lexeme::new(0, 0, 2)), Err(3),
+                                                     Ok(Lexeme::new(0, 4, 1))]);
+        assert_eq!(feed(&mut lexer, "d\n"), vec![Ok(Lexeme::new(0, 6, 1))]);
+        assert_eq!(lexer.finish().unwrap(), vec![Lexeme::new(2, 8, 0)]);
+    }
+
+    #[test]
+    fn test_line_lexer_modes() {
+        // Constructs which span lines are lexed with modes, and are then lexed as they would be if
+        // the whole input were lexed at once.
+        let src = "%%
+/ 'DIV'
+/\\* 'OPEN'
+\\*/ 'CLOSE'
+[^*]+|\\* ;
+\" 'QUOTE'
+\" 'ENDQUOTE'
+[^\"]+ 'TEXT'
+[a-z]+ 'ID'
+[ \\n] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        let names = ["DIV", "OPEN", "CLOSE", "QUOTE", "ENDQUOTE", "TEXT", "ID"];
+        for (i, n) in names.iter().enumerate() {
+            map.insert(*n, i as u8);
+        }
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        const NORMAL: usize = 0;
+        const COMMENT: usize = 1;
+        const STRING: usize = 2;
+        let modes = [NORMAL, NORMAL, COMMENT, COMMENT, NORMAL, STRING, STRING, NORMAL, NORMAL];
+        for (r, &m) in lexerdef.rules.iter_mut().zip(modes.iter()) {
+            r.modes = Some(vec![m]);
+        }
+        lexerdef.rules[1].set_mode = Some(COMMENT);
+        lexerdef.rules[2].set_mode = Some(NORMAL);
+        lexerdef.rules[4].set_mode = Some(STRING);
+        lexerdef.rules[5].set_mode = Some(NORMAL);
+
+        let lex_lines = |lines: &[&str]| {
+            let mut lexer = lexerdef.line_lexer();
+            let mut lxs = Vec::new();
+            for line in lines {
+                lxs.extend(lexer.feed_line(line).into_iter().map(|r| r.unwrap()));
+            }
+            lxs.extend(lexer.finish().unwrap());
+            assert_eq!(lxs, lexerdef.lexer(&lines.concat()).lexemes().unwrap());
+            lxs
+        };
+        assert_eq!(lex_lines(&["/* ab\n", "cd */\n"]),
+                   vec![Lexeme::new(1, 0, 2), Lexeme::new(2, 9, 2)]);
+        assert_eq!(lex_lines(&["\"a\n", "b\n", "c\"\n"]),
+                   vec![Lexeme::new(3, 0, 1), Lexeme::new(5, 1, 5), Lexeme::new(4, 6, 1)]);
+    }
+
+    #[test]
+    fn test_tokens_equal_ignoring_trivia() {
+        let src = "%%
+[a-z]+ 'ID'
+#[^\\n]* 'COMMENT'
+[ \\n] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("COMMENT", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let a_src = "ab cd # x";
+        let a = lexerdef.lexer(a_src).lexemes().unwrap();
+        let b_src = "ab\n#y\n  cd";
+        let b = lexerdef.lexer(b_src).lexemes().unwrap();
+        assert!(tokens_equal_ignoring_trivia(a_src, &a, b_src, &b, &[1]));
+        assert!(!tokens_equal_ignoring_trivia(a_src, &a, b_src, &b, &[]));
+        let c_src = "ab ce";
+        let c = lexerdef.lexer(c_src).lexemes().unwrap();
+        assert!(!tokens_equal_ignoring_trivia(a_src, &a, c_src, &c, &[1]));
+        let d_src = "ab";
+        let d = lexerdef.lexer(d_src).lexemes().unwrap();
+        assert!(!tokens_equal_ignoring_trivia(a_src, &a, d_src, &d, &[1]));
+    }
+
+    #[test]
+    fn test_literal() {
+        let src = "%%
+if 'IF'
+\\+= 'PLUSEQ'
+[a-z]+ 'ID'
+%rest 'REST'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("IF", 0);
+        map.insert("PLUSEQ", 1);
+        map.insert("ID", 2);
+        map.insert("REST", 3);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        assert_eq!(lexerdef.iter_rules().map(|r| r.literal()).collect::<Vec<_>>(),
+                   vec![Some("if"), Some("+="), None, None, Some(" ")]);
+
+        let lexemes = lexerdef.lexer("if ifx += i").lexemes().unwrap();
+        assert_eq!(lexemes, vec![Lexeme::new(0, 0, 2), Lexeme::new(2, 3, 3),
+                                 Lexeme::new(1, 7, 2), Lexeme::new(2, 10, 1)]);
+        let lexemes = lexerdef.lexer("if +").lexemes().unwrap();
+        assert_eq!(lexemes, vec![Lexeme::new(0, 0, 2), Lexeme::new(3, 3, 1)]);
+    }
+
+    #[test]
+    fn test_block_indent() {
+        let src = "%%
+[a-z]+ 'ID'
+: 'COLON'
+\\| 'PIPE'
+%block-indent 'BLOCK'
+[ \\r\\n] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("COLON", 1);
+        map.insert("PIPE", 2);
+        map.insert("BLOCK", 3);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        assert!(lexerdef.get_rule_by_name("BLOCK").unwrap().is_block_indent());
+        assert_eq!(lexerdef.get_rule_by_name("BLOCK").unwrap().literal(), None);
+        fn after_pipe(prev: Option<u8>) -> bool { prev == Some(2) }
+        lexerdef.get_rule_by_name_mut("BLOCK").unwrap().after = Some(after_pipe);
+
+        let s = "a: |\n  x\n\n   y\n\nb: |\n c\n  d: e\n";
+        let lexer = lexerdef.lexer(s);
+        let lexemes = lexer.lex_with_text().unwrap();
+        assert_eq!(lexemes.iter().map(|&(_, t)| t).collect::<Vec<_>>(),
+                   vec!["a", ":", "|", "\n  x\n\n   y", "b", ":", "|", "\n c\n  d: e"]);
+        assert_eq!(lexemes[3].0.tok_id(), 3);
+
+        let lexemes = lexerdef.lexer("  a: |\r\n    x\r\n  b").lex_with_text().unwrap();
+        assert_eq!(lexemes[3].1, "\r\n    x");
+    }
+
+    #[test]
+    fn test_highlight_spans() {
+        let src = "%%
+%category 'keyword'
+if 'IF'
+[a-z]+ 'ID'
+%category 'number'
+[0-9]+ 'INT'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("IF", 0);
+        map.insert("ID", 1);
+        map.insert("INT", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let lexer = lexerdef.lexer("if x 12 !! ifs ?");
+        assert_eq!(lexer.highlight_spans(),
+                   vec![((0, 2), Category::Named("keyword")),
+                        ((5, 2), Category::Named("number")),
+                        ((8, 2), Category::Error),
+                        ((15, 1), Category::Error)]);
+    }
+
+    #[test]
+    fn test_prebuilt_rules() {
+        let mut lexerdef = LexerDef::new(vec![Rule::c_integer("INT"),
+                                              Rule::c_identifier("ID"),
+                                              Rule::whitespace()]);
+        let mut map = HashMap::new();
+        map.insert("INT", 0);
+        map.insert("ID", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        assert_eq!(lexerdef.lexer("x_1 0x1fUL\n\t_ 077 9").lexemes().unwrap(),
+                   vec![Lexeme::new(1, 0, 3), Lexeme::new(0, 4, 6), Lexeme::new(1, 12, 1),
+                        Lexeme::new(0, 14, 3), Lexeme::new(0, 18, 1)]);
+        assert!(lexerdef.lexer("1$").lexemes().is_err());
+    }
+
+    #[test]
+    fn test_balanced() {
+        let src = "%%
+[a-z]+!\\( 'MACRO'
+[a-z]+ 'ID'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("MACRO", 0);
+        map.insert("ID", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        lexerdef.get_rule_by_name_mut("MACRO").unwrap().balanced =
+            Some(Balanced::new('(', ')').skip("\"[^\"]*\"").unwrap());
+
+        let s = "m!(a (b) \")(\" c) x";
+        assert_eq!(lexerdef.lexer(s).lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 16), Lexeme::new(1, 17, 1)]);
+        let s = "x m!(a (b c";
+        match lexerdef.lexer(s).lexemes() {
+            Err(LexError{idx: 2, len: 9, cause: LexErrorCause::Unbalanced}) => (),
+            r => panic!("Incorrect result {:?}", r)
+        }
+        let out = lexerdef.lexer(s).lex_all();
+        assert_eq!(out.lexemes, vec![Lexeme::new(1, 0, 1)]);
+        assert_eq!(out.errors.iter().map(|e| e.cause()).collect::<Vec<_>>(),
+                   vec![LexErrorCause::Unbalanced]);
+
+        lexerdef.get_rule_by_name_mut("MACRO").unwrap().balanced =
+            Some(Balanced::new('(', ')').max_depth(2));
+        assert_eq!(lexerdef.lexer("m!(a (b) (c)) x").lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 13), Lexeme::new(1, 14, 1)]);
+        match lexerdef.lexer("m!(a (b (c))) x").lexemes() {
+            Err(LexError{idx: 0, len: 9, cause: LexErrorCause::NestingTooDeep}) => (),
+            r => panic!("Incorrect result {:?}", r)
+        }
+        let s = format!("m!{}", "(".repeat(10_000));
+        assert_eq!(lexerdef.lexer(&s).lex_all().errors[0].cause(), LexErrorCause::NestingTooDeep);
+    }
+
+    #[test]
+    fn test_nul_policy() {
+        let src = "%%
+[a-z]+ 'ID'
+[^ ]+ 'OTHER'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("OTHER", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "ab 1\x002 c";
+        assert_eq!(lexerdef.lexer(s).lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 2), Lexeme::new(1, 3, 3), Lexeme::new(0, 7, 1)]);
+        assert_eq!(lexerdef.lexer(s).nul_policy(NulPolicy::Eof).lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 2), Lexeme::new(1, 3, 1)]);
+        // The input after the NUL isn't lexed, but is still part of the lexer's input.
+        let lexer = lexerdef.lexer(s).nul_policy(NulPolicy::Eof);
+        assert_eq!(lexer.line_and_col(&Lexeme::new(0, 7, 1)), Ok((1, 8)));
+        assert_eq!(lexer.longest_match_at(3), Some((1, 1)));
+        assert_eq!(lexer.longest_match_at(7), None);
+        match lexerdef.lexer(s).nul_policy(NulPolicy::Error).lexemes() {
+            Err(LexError{idx: 4, len: 1, cause: LexErrorCause::Nul}) => (),
+            r => panic!("Incorrect result {:?}", r)
+        }
+        let out = lexerdef.lexer("\0a\0\0 b").nul_policy(NulPolicy::Error).lex_all();
+        assert_eq!(out.lexemes, vec![Lexeme::new(0, 1, 1), Lexeme::new(0, 5, 1)]);
+        assert_eq!(out.errors.iter().map(|e| (e.idx(), e.len())).collect::<Vec<_>>(),
+                   vec![(0, 1), (2, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn test_match_window() {
+        let src = "%%
+[0-9]+ 'INT'
+/\\*(?:[^*]|\\*[^/])*\\*/ 'COMMENT'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("INT", 0);
+        map.insert("COMMENT", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "1234567 /* abcdef */ 12";
+        assert_eq!(lexerdef.lexer(s).lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 7), Lexeme::new(1, 8, 12), Lexeme::new(0, 21, 2)]);
+        assert_eq!(lexerdef.lexer(s).match_window(4).lexemes().unwrap_err().idx(), 8);
+        lexerdef.get_rule_by_name_mut("COMMENT").unwrap().unbounded_match = true;
+        assert_eq!(lexerdef.lexer(s).match_window(4).lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 4), Lexeme::new(0, 4, 3), Lexeme::new(1, 8, 12),
+                        Lexeme::new(0, 21, 2)]);
+        // Windows are rounded up to a character boundary.
+        let lexemes = lexerdef.lexer("\u{e9}").match_window(1).lexemes();
+        assert_eq!(lexemes.unwrap_err().len(), 2);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let src = "%%
+[a-z]+ 'ID'
+\" 'QUOTE'
+[^\"]+ 'STR'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex::<u8>(&src).unwrap();
+        lexerdef.get_rule_by_name_mut("ID").unwrap().modes = Some(vec![0]);
+        lexerdef.get_rule_by_name_mut("STR").unwrap().modes = Some(vec![1]);
+        lexerdef.get_rule_by_name_mut("QUOTE").unwrap().modes = Some(vec![0]);
+        lexerdef.get_rule_by_name_mut("QUOTE").unwrap().set_mode = Some(1);
+        assert_eq!(lexerdef.to_dot(), concat!("digraph lexer {\n",
+                                              "    m0 [label=\"mode 0\\n'ID'\\n'QUOTE'\\n[ ]\"];\n",
+                                              "    m1 [label=\"mode 1\\n'STR'\\n[ ]\"];\n",
+                                              "    m0 -> m1 [label=\"'QUOTE'\"];\n",
+                                              "}\n"));
+    }
+
+    #[test]
+    fn test_lex_with_text() {
+        let src = "%%
+[a-z]+ 'ID'
+[0-9]+ 'INT'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("INT", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let input = "ab 12".to_string();
+        let lxs = {
+            let lexer = lexerdef.lexer(&input);
+            lexer.lex_with_text().unwrap()
+        };
+        assert_eq!(lxs, vec![(Lexeme::new(0, 0, 2), "ab"), (Lexeme::new(1, 3, 2), "12")]);
+    }
+
+    #[test]
+    fn test_reject() {
+        let src = "%%
+[a-z]+ 'ID'
+[a-z]+[0-9]* 'IDNUM'
+[a-z] 'CHAR'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("IDNUM", 1);
+        map.insert("CHAR", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        fn reserved(s: &str) -> bool { s == "if" }
+        lexerdef.get_rule_by_name_mut("ID").unwrap().reject = Some(reserved);
+        lexerdef.get_rule_by_name_mut("IDNUM").unwrap().reject = Some(reserved);
+
+        let lexemes = lexerdef.lexer("ab if").lexemes().unwrap();
+        assert_eq!(lexemes, vec![Lexeme::new(0, 0, 2), Lexeme::new(2, 3, 1),
+                                 Lexeme::new(0, 4, 1)]);
+    }
+
+    #[test]
+    fn test_max_len() {
+        let src = "%%
+[a-z]+ 'ID'
+\"[^\"]*\" 'STR'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("STR", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        lexerdef.get_rule_by_name_mut("ID").unwrap().max_len = Some(3);
+
+        assert_eq!(lexerdef.lexer("abc \"defgh\"").lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 3), Lexeme::new(1, 4, 7)]);
+        match lexerdef.lexer("abc abcd").lexemes() {
+            Err(LexError{idx: 4, len: 4, cause: LexErrorCause::TooLong{id: 0}}) => (),
+            r => panic!("Incorrect result {:?}", r)
+        }
+        let out = lexerdef.lexer("abcd# ab").lex_all();
+        assert_eq!(out.lexemes, vec![Lexeme::new(0, 6, 2)]);
+        assert_eq!(out.errors.iter().map(|e| (e.idx(), e.len(), e.cause())).collect::<Vec<_>>(),
+                   vec![(0, 4, LexErrorCause::TooLong{id: 0}), (4, 1, LexErrorCause::Unmatched)]);
+    }
+
+    #[test]
+    fn test_utf16_offsets() {
+        let src = "%%
+\\S+ 'W'
+\\s ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("W", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "a\u{e9}\u{1f600}b\nc\u{1f600} d";
+        let lexer = lexerdef.lexer(s);
+        assert_eq!(lexer.char_offset(0), Some(0));
+        assert_eq!(lexer.char_offset(1 + 2 + 4), Some(3));
+        assert_eq!(lexer.utf16_offset(1 + 2 + 4), Some(4));
+        assert_eq!(lexer.utf16_offset(s.len()), Some(11));
+        assert_eq!(lexer.utf16_offset(2), None);
+        assert_eq!(lexer.utf16_offset(s.len() + 1), None);
+        assert_eq!(lexer.line_col_utf16(1 + 2 + 4), Some((1, 5)));
+        assert_eq!(lexer.line_col_utf16(s.find('c').unwrap()), Some((2, 1)));
+        assert_eq!(lexer.line_col_utf16(s.find('d').unwrap()), Some((2, 5)));
+        assert_eq!(lexer.line_col_utf16(0), Some((1, 1)));
+    }
+
+    #[cfg(feature = "proc-macro2")]
+    #[test]
+    fn test_line_columns() {
+        let src = "%%
+\\S+ 'W'
+\\s ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("W", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "a\u{e9}\u{1f600}b\nc\u{1f600} d";
+        let lexer = lexerdef.lexer(s);
+        let lexemes = lexer.lexemes().unwrap();
+        let lc = |line, column| LineColumn{line, column};
+        assert_eq!(lexer.line_columns(lexemes[0].start(), lexemes[0].len()),
+                   Some((lc(1, 0), lc(1, 4))));
+        assert_eq!(lexer.line_columns(lexemes[1].start(), lexemes[1].len()),
+                   Some((lc(2, 0), lc(2, 2))));
+        assert_eq!(lexer.line_columns(lexemes[2].start(), lexemes[2].len()),
+                   Some((lc(2, 3), lc(2, 4))));
+        assert_eq!(lexer.line_columns(1, 1), None);
+        assert_eq!(lexer.line_columns(s.len(), 1), None);
+    }
 
-        let lexer = lexerdef.lexer("a b c\n");
+    #[test]
+    fn test_unicode_line_separators() {
+        let src = "%%
+[a-z]+ 'ID'
+[ \\n\\u{2028}\\u{2029}] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "a\u{2028}b\nc\u{2029} d";
+        let lexer = lexerdef.lexer(s);
         let lexemes = lexer.lexemes().unwrap();
-        assert_eq!(lexemes.len(), 3);
-        assert_eq!(lexer.line_and_col(&lexemes[1]).unwrap(), (1, 3));
+        assert_eq!(lexer.line_and_col(&lexemes[1]).unwrap(), (1, 5));
+        assert_eq!(lexer.line_and_col(&lexemes[3]).unwrap(), (2, 6));
+        assert_eq!(lexer.line_col_utf16(lexemes[3].start()), Some((2, 4)));
 
-        let lexer = lexerdef.lexer(" a\nb\n  c d");
+        let lexer = lexerdef.lexer(s).unicode_line_separators(true);
         let lexemes = lexer.lexemes().unwrap();
-        assert_eq!(lexemes.len(), 4);
-        assert_eq!(lexer.line_and_col(&lexemes[0]).unwrap(), (1, 2));
         assert_eq!(lexer.line_and_col(&lexemes[1]).unwrap(), (2, 1));
-        assert_eq!(lexer.line_and_col(&lexemes[2]).unwrap(), (3, 3));
-        assert_eq!(lexer.line_and_col(&lexemes[3]).unwrap(), (3, 5));
+        assert_eq!(lexer.line_and_col(&lexemes[2]).unwrap(), (3, 1));
+        assert_eq!(lexer.line_and_col(&lexemes[3]).unwrap(), (4, 2));
+        assert_eq!(lexer.line_col_utf16(lexemes[3].start()), Some((4, 2)));
+    }
 
-        let fake_lexeme = Lexeme{start: 100, len: 1, tok_id: 0};
-        if let Ok(_) = lexer.line_and_col(&fake_lexeme) {
-            panic!("line_and_col returned Ok(_) when it should have returned Err.");
+    #[test]
+    fn test_error_spans() {
+        let src = "%%
+[0-9]+ 'INT'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("INT", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        assert_eq!(lexerdef.error_spans("1 2 3"), vec![]);
+        assert_eq!(lexerdef.error_spans("1 ab 2\u{e9}"), vec![(2, 2), (6, 2)]);
+        assert_eq!(lexerdef.error_spans("1 ab 2\u{e9}"),
+                   lexerdef.lexer("1 ab 2\u{e9}").lex_all()
+                                                .errors
+                                                .iter()
+                                                .map(|e| (e.idx(), e.len()))
+                                                .collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_after() {
+        let src = "%%
+[0-9]+ 'INT'
+- 'NEG'
+- 'SUB'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("INT", 0);
+        map.insert("NEG", 1);
+        map.insert("SUB", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        fn unary(prev: Option<u8>) -> bool { prev != Some(0) }
+        lexerdef.get_rule_by_name_mut("NEG").unwrap().after = Some(unary);
+
+        let lexemes = lexerdef.lexer("-1 - -2").lexemes().unwrap();
+        assert_eq!(lexemes.iter().map(|l| l.tok_id()).collect::<Vec<_>>(), vec![1, 0, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_from_terminals() {
+        let lexerdef = LexerDef::<u8>::from_terminals(&[("PLUS", "+"), ("INC", "++"),
+                                                         ("LPAREN", "(")]).unwrap();
+        assert_eq!(lexerdef.get_rule_by_name("INC").unwrap().re_str, "\\+\\+");
+        assert_eq!(lexerdef.lexer("+++(").lexemes().unwrap(),
+                   vec![Lexeme::new(1, 0, 2), Lexeme::new(0, 2, 1), Lexeme::new(2, 3, 1)]);
+        match LexerDef::<u8>::from_terminals(&[("PLUS", "+"), ("PLUS", "++")]) {
+            Err(LexBuildError{kind: LexErrorKind::DuplicateName, ..}) => (),
+            _ => panic!()
         }
     }
 
     #[test]
-    fn test_missing_from_lexer_and_parser() {
+    fn test_merge_errors_across_skips() {
         let src = "%%
 [a-z]+ 'ID'
 [ \\n] ;".to_string();
         let mut lexerdef = parse_lex(&src).unwrap();
         let mut map = HashMap::new();
-        map.insert("INT", 0);
-        let mut missing_from_lexer = HashSet::new();
-        missing_from_lexer.insert("INT");
-        let mut missing_from_parser = HashSet::new();
-        missing_from_parser.insert("ID");
-        assert_eq!(lexerdef.set_rule_ids(&map), (Some(missing_from_lexer), Some(missing_from_parser)));
+        map.insert("ID", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
 
-        match lexerdef.lexer(&" a ").lexemes() {
-            Ok(_)  => panic!("Invalid input lexed"),
-            Err(LexError{idx: 1}) => (),
-            Err(e) => panic!("Incorrect error returned {:?}", e)
-        };
+        let s = "a 12 3\n4 b !";
+        let out = lexerdef.lexer(s).lex_all();
+        assert_eq!(out.errors.iter().map(|e| (e.idx(), e.len())).collect::<Vec<_>>(),
+                   vec![(2, 2), (5, 1), (7, 1), (11, 1)]);
+        let out = lexerdef.lexer(s).merge_errors_across_skips(true).lex_all();
+        assert_eq!(out.lexemes, vec![Lexeme::new(0, 0, 1), Lexeme::new(0, 9, 1)]);
+        assert_eq!(out.errors.iter().map(|e| (e.idx(), e.len())).collect::<Vec<_>>(),
+                   vec![(2, 6), (11, 1)]);
+
+        // A dropped error must not cause a later error to be merged into an earlier one.
+        let lexer = lexerdef.lexer("1 a 2 3").merge_errors_across_skips(true);
+        assert_eq!(lexer.lex_all().errors.iter().map(|e| (e.idx(), e.len())).collect::<Vec<_>>(),
+                   vec![(0, 1), (4, 3)]);
+        let out = lexer.max_errors(1).lex_all();
+        assert_eq!(out.errors.iter().map(|e| (e.idx(), e.len())).collect::<Vec<_>>(),
+                   vec![(0, 1)]);
+        assert!(out.errors_truncated);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let src = "%%
+[a-z]+ 'ID'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "a b c";
+        let later = Instant::now() + ::std::time::Duration::from_secs(3600);
+        assert_eq!(lexerdef.lexer(s).deadline(later).lexemes().unwrap().len(), 3);
+        match lexerdef.lexer(s).deadline(Instant::now()).lexemes() {
+            Err(LexError{idx: 0, len: 0, cause: LexErrorCause::Timeout}) => (),
+            r => panic!("Incorrect result {:?}", r)
+        }
+        let out = lexerdef.lexer(s).deadline(Instant::now()).lex_all();
+        assert!(out.lexemes.is_empty());
+        assert_eq!(out.errors.iter().map(|e| e.cause()).collect::<Vec<_>>(),
+                   vec![LexErrorCause::Timeout]);
+    }
+
+    #[test]
+    fn test_max_lexemes() {
+        let src = "%%
+[a-z]+ 'ID'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "a b c";
+        assert_eq!(lexerdef.lexer(s).max_lexemes(3).lexemes().unwrap().len(), 3);
+        match lexerdef.lexer(s).max_lexemes(2).lexemes() {
+            Err(LexError{idx: 4, len: 0, cause: LexErrorCause::TooManyTokens}) => (),
+            r => panic!("Incorrect result {:?}", r)
+        }
+        let out = lexerdef.lexer(s).max_lexemes(1).lex_all();
+        assert_eq!(out.lexemes, vec![Lexeme::new(0, 0, 1)]);
+        assert_eq!(out.errors.iter().map(|e| (e.idx(), e.cause())).collect::<Vec<_>>(),
+                   vec![(2, LexErrorCause::TooManyTokens)]);
+    }
+
+    #[test]
+    fn test_eof() {
+        let src = "%%
+[a-z]+ 'ID'
+[ ] ;
+%eof 'EOF'".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("EOF", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        assert!(lexerdef.get_rule_by_name("EOF").unwrap().is_eof());
+
+        assert_eq!(lexerdef.lexer("").lexemes().unwrap(), vec![Lexeme::new(1, 0, 0)]);
+        assert_eq!(lexerdef.lexer("a b ").lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 1), Lexeme::new(0, 2, 1), Lexeme::new(1, 4, 0)]);
+        let mut lexer = lexerdef.lexer("a");
+        assert_eq!(lexer.next().unwrap().unwrap(), Lexeme::new(0, 0, 1));
+        assert_eq!(lexer.next().unwrap().unwrap(), Lexeme::new(1, 1, 0));
+        assert!(lexer.next().is_none());
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn test_error_rules() {
+        let src = "%%
+[a-z]+ 'ID'
+\"[^\"]*\" 'STR'
+%error 'unterminated string literal'
+\"[^\"]* 'BADSTR'
+%error 'unexpected digits'
+[0-9]+ ;
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("STR", 1);
+        map.insert("BADSTR", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        assert_eq!(lexerdef.lexer("a \"b\"").lexemes().unwrap(),
+                   vec![Lexeme::new(0, 0, 1), Lexeme::new(1, 2, 3)]);
+        let e = lexerdef.lexer("a \"b").lexemes().unwrap_err();
+        assert_eq!((e.idx(), e.len(), e.cause()), (2, 2, LexErrorCause::Rule{ridx: 2}));
+        assert_eq!(lexerdef.error_message(&e), Some("unterminated string literal"));
+        let out = lexerdef.lexer("a 12 b").lex_all();
+        assert_eq!(out.lexemes, vec![Lexeme::new(0, 0, 1), Lexeme::new(0, 5, 1)]);
+        assert_eq!(lexerdef.error_message(&out.errors[0]), Some("unexpected digits"));
+        let e = lexerdef.lexer("$").lexemes().unwrap_err();
+        assert_eq!(lexerdef.error_message(&e), None);
+    }
+
+    #[test]
+    fn test_lex_prefix() {
+        let src = "%%
+[a-z]+ 'ID'
+%eof 'EOF'
+[ \\n] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("EOF", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "ab cde\n$";
+        assert_eq!(lexerdef.lex_prefix(s, 0).unwrap(), (vec![], true));
+        assert_eq!(lexerdef.lex_prefix(s, 5).unwrap(), (vec![Lexeme::new(0, 0, 2)], true));
+        assert_eq!(lexerdef.lex_prefix(s, 6).unwrap(),
+                   (vec![Lexeme::new(0, 0, 2), Lexeme::new(0, 3, 3)], true));
+        assert_eq!(lexerdef.lex_prefix(s, 8).unwrap_err().idx(), 7);
+        let s = "ab\n";
+        assert_eq!(lexerdef.lex_prefix(s, 2).unwrap(), (vec![Lexeme::new(0, 0, 2)], true));
+        assert_eq!(lexerdef.lex_prefix(s, 3).unwrap(),
+                   (vec![Lexeme::new(0, 0, 2), Lexeme::new(1, 3, 0)], false));
+
+        // A match which extends beyond the prefix isn't mistaken for an error within it.
+        let src = "%%
+[a-z]+ 'ID'
+\"[^\"]*\" 'STR'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("STR", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        let s = "ab \"cdef\" \"gh";
+        assert_eq!(lexerdef.lex_prefix(s, 5).unwrap(), (vec![Lexeme::new(0, 0, 2)], true));
+        assert_eq!(lexerdef.lex_prefix(s, 12).unwrap_err().idx(), 10);
+
+        // A shorter match within the prefix isn't preferred to a longer one beyond it.
+        let src = "%%
+a 'A'
+abc 'ABC'
+abcde 'ABCDE'".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("A", 0);
+        map.insert("ABC", 1);
+        map.insert("ABCDE", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        assert_eq!(lexerdef.lexer("abc").lexemes().unwrap(), vec![Lexeme::new(1, 0, 3)]);
+        assert_eq!(lexerdef.lex_prefix("abc", 1).unwrap(), (vec![], true));
+        assert_eq!(lexerdef.lex_prefix("abcde", 2).unwrap(), (vec![], true));
+        assert_eq!(lexerdef.lex_prefix("abca", 3).unwrap(), (vec![Lexeme::new(1, 0, 3)], true));
+        assert_eq!(lexerdef.lex_prefix("abca", 4).unwrap(),
+                   (vec![Lexeme::new(1, 0, 3), Lexeme::new(0, 3, 1)], false));
+    }
+
+    #[test]
+    fn test_lexer_from_bytes() {
+        let src = "%%
+[a-z]+ 'ID'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let b = b"ab c".to_vec();
+        let lexer = lexerdef.lexer_from_bytes(&b).unwrap();
+        assert_eq!(lexer.lex_with_text().unwrap()[1].1.as_ptr(), b[3..].as_ptr());
+        assert!(lexerdef.lexer_from_bytes(b"a\xff").is_err());
+    }
+
+    #[test]
+    fn test_longest_match_at() {
+        let src = "%%
+[a-z]+ 'ID'
+[a-z]+[0-9]+ 'IDNUM'
+[ ] ;
+%eof 'EOF'".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("IDNUM", 1);
+        map.insert("EOF", 2);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        lexerdef.get_rule_by_name_mut("IDNUM").unwrap().modes = Some(vec![1]);
+
+        let s = "ab1 c!";
+        let lexer = lexerdef.lexer(s);
+        assert_eq!(lexer.longest_match_at(0), Some((0, 2)));
+        assert_eq!(lexer.longest_match_at(3), Some((2, 1)));
+        assert_eq!(lexer.longest_match_at(5), None);
+        assert_eq!(lexer.longest_match_at(6), Some((3, 0)));
+        assert_eq!(lexer.longest_match_at(7), None);
+        assert_eq!(lexerdef.lexer(s).initial_mode(1).longest_match_at(0), Some((1, 3)));
+    }
+
+    #[test]
+    fn test_lex_by_logical_line() {
+        let src = "%%
+[a-z]+ 'ID'
+\"[^\"]*\" 'STR'
+[ \\n\\r\\\\] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("STR", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "a b\\\nc\n\nd \"x\ny\" e\\\r\nf\ng";
+        let lines = lexerdef.lexer(s).lex_by_logical_line().unwrap();
+        assert_eq!(lines.iter()
+                        .map(|ls| ls.iter().map(|l| &s[l.start()..l.start() + l.len()])
+                                            .collect::<Vec<_>>())
+                        .collect::<Vec<_>>(),
+                   vec![vec!["a", "b", "c"], vec!["d", "\"x\ny\"", "e", "f"], vec!["g"]]);
+    }
+
+    #[test]
+    fn test_segments() {
+        let src = "%%
+[a-z]+ 'ID'
+[ \\n]+ ;
+#[^\\n]* ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let s = "  a b #c\n d \n";
+        let segs = lexerdef.lexer(s).segments().unwrap();
+        assert_eq!(segs, vec![Segment::Trivia(0, 2), Segment::Token(Lexeme::new(0, 2, 1)),
+                              Segment::Trivia(3, 1), Segment::Token(Lexeme::new(0, 4, 1)),
+                              Segment::Trivia(5, 5), Segment::Token(Lexeme::new(0, 10, 1)),
+                              Segment::Trivia(11, 2)]);
+        for s in &[s, "", " ", "a", "a b"] {
+            let text = lexerdef.lexer(s)
+                               .segments()
+                               .unwrap()
+                               .iter()
+                               .map(|seg| match *seg {
+                                   Segment::Trivia(start, len) => &s[start..start + len],
+                                   Segment::Token(l) => &s[l.start()..l.start() + l.len()]
+                               })
+                               .collect::<String>();
+            assert_eq!(&text, s);
+        }
+    }
+
+    #[test]
+    fn test_lex_grouped() {
+        let src = "%%
+[a-z]+ 'ID'
+[0-9]+ 'INT'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("INT", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+
+        let groups = lexerdef.lexer("a 1 bc 23 d").lex_grouped().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[&0], vec![Lexeme::new(0, 0, 1), Lexeme::new(0, 4, 2),
+                                    Lexeme::new(0, 10, 1)]);
+        assert_eq!(groups[&1], vec![Lexeme::new(1, 2, 1), Lexeme::new(1, 7, 2)]);
+        assert!(lexerdef.lexer("a !").lex_grouped().is_err());
     }
 }
@@ -33,20 +33,39 @@
 #![feature(fs_read_write)]
 #![feature(try_from)]
 
+#[cfg(feature = "proc-macro2")]
+extern crate proc_macro2;
 extern crate regex;
 extern crate typename;
 
 use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
+use std::path::PathBuf;
 
 mod builder;
+#[cfg(feature = "cache")]
+mod cache;
+mod escapes;
+#[cfg(feature = "ffi")]
+mod ffi;
 mod lexer;
 mod parser;
+mod pipeline;
+mod scanner;
+mod testing;
 
-pub use builder::{process_file, process_file_in_src};
-pub use lexer::{Lexeme, LexerDef, Lexer, Rule};
-use parser::parse_lex;
+pub use builder::{LexerBuilder, process_file, process_file_in_src};
+pub use escapes::EscapeStyle;
+#[cfg(feature = "ffi")]
+pub use ffi::{lex_to_arrays, lrlex_lex, lrlex_lexemes_free, LexemeArrays};
+pub use lexer::{check_delimiters, tokens_equal_ignoring_trivia, Balanced, Category,
+                DelimError, LexAllOutput, LexError, LexErrorCause, Lexeme, LexerDef, Lexer,
+                LexerCheckpoint, LineLexer, MatchPolicy, ModeEvent, NulPolicy, OwnedLexer,
+                Rule, Segment};
+pub use pipeline::Pipeline;
+pub use testing::assert_tokens;
+use parser::{parse_lex, parse_lex_lenient};
 
 pub type LexBuildResult<T> = Result<T, LexBuildError>;
 
@@ -63,7 +82,7 @@ impl Error for LexBuildError {
         panic!("XXX");
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn cause(&self) -> Option<&dyn Error> {
         None
     }
 }
@@ -77,7 +96,19 @@ pub enum LexErrorKind {
     MissingSpace,
     InvalidName,
     DuplicateName,
-    RegexError
+    RegexError,
+    /// A rule's regex nests groups, classes, or repetitions more deeply than the given limit
+    /// (which can be raised with
+    /// [`LexerBuilder::nest_limit`](struct.LexerBuilder.html#method.nest_limit)).
+    RegexNestLimit(u32),
+    InvalidDirective,
+    /// The path of an input file passed to the builder does not have a `.l` extension. Errors of
+    /// this kind have no meaningful line or column.
+    BadInputPath(PathBuf),
+    /// The names of several rules in the rule IDs map passed to the builder (given in sorted
+    /// order) are the same once ASCII-uppercased, so they would generate the same `T_` constant.
+    /// Errors of this kind have no meaningful line or column.
+    ConstantNameCollision(Vec<String>)
 }
 
 impl fmt::Display for LexBuildError {
@@ -90,7 +121,22 @@ impl fmt::Display for LexBuildError {
             LexErrorKind::MissingSpace         => s = "Rule is missing a space",
             LexErrorKind::InvalidName          => s = "Invalid rule name",
             LexErrorKind::DuplicateName        => s = "Rule name already exists",
-            LexErrorKind::RegexError           => s = "Invalid regular expression"
+            LexErrorKind::RegexError           => s = "Invalid regular expression",
+            LexErrorKind::InvalidDirective     => s = "Invalid or misplaced directive",
+            LexErrorKind::RegexNestLimit(l)    => {
+                return write!(f, "Regular expression is nested more deeply than the limit of {} \
+                                  at line {} column {}", l, self.line, self.col);
+            },
+            LexErrorKind::BadInputPath(ref p)  => {
+                return write!(f, "Input file '{}' must have the extension '.l'", p.display());
+            },
+            LexErrorKind::ConstantNameCollision(ref names) => {
+                return write!(f, "Rule names {} would generate the same constant name",
+                              names.iter()
+                                   .map(|n| format!("'{}'", n))
+                                   .collect::<Vec<_>>()
+                                   .join(", "));
+            }
         }
         write!(f, "{} at line {} column {}", s, self.line, self.col)
     }
@@ -100,6 +146,19 @@ pub fn build_lex<TokId: Copy + Eq + TryFrom<usize>>(s: &str) -> Result<LexerDef<
     parse_lex(s)
 }
 
+/// As [`build_lex`](fn.build_lex.html), but rules whose regexes fail to compile are omitted from
+/// the resulting `LexerDef` rather than causing building to fail. The errors for such rules are
+/// returned alongside the `LexerDef` (as warnings, in the order the rules appear in `s`): if
+/// there are none, the `LexerDef` is the same as that returned by `build_lex`. Other errors (e.g.
+/// an invalid rule name) are still fatal. Note that rules are given IDs as if the omitted rules
+/// were not present.
+pub fn build_lex_lenient<TokId>(s: &str)
+                             -> Result<(LexerDef<TokId>, Vec<LexBuildError>), LexBuildError>
+                      where TokId: Copy + Eq + TryFrom<usize>
+{
+    parse_lex_lenient(s)
+}
+
 /// A convenience macro for including statically compiled `.l` files. A file `src/x.l` which is
 /// statically compiled by lrlex can then be used in a crate with `lrlex_mod!(x)`.
 #[macro_export]
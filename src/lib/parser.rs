@@ -35,18 +35,45 @@ use std::convert::TryFrom;
 use {LexErrorKind, LexBuildError, LexBuildResult};
 use lexer::{LexerDef, Rule};
 
+/// The nesting limit the `regex` crate uses if none is given explicitly.
+const DEFAULT_NEST_LIMIT: u32 = 250;
+
 pub struct LexParser<TokId> {
     src: String,
     newlines: Vec<usize>,
-    rules: Vec<Rule<TokId>>
+    rules: Vec<Rule<TokId>>,
+    /// A `%doc` directive (and its offset in `src`) which has not yet been attached to a rule.
+    pending_doc: Option<(String, usize)>,
+    /// As `pending_doc`, but for a `%category` directive.
+    pending_category: Option<(String, usize)>,
+    /// As `pending_doc`, but for an `%error` directive.
+    pending_error: Option<(String, usize)>,
+    /// The ID given to the first rule: subsequent rules are given consecutive IDs.
+    id_base: usize,
+    /// The nesting limit rules' regexes are compiled with, or `None` for the `regex` crate's
+    /// default.
+    nest_limit: Option<u32>,
+    /// If `true`, rules whose regexes fail to compile are omitted (with their errors recorded in
+    /// `omitted`) rather than causing parsing to fail.
+    lenient: bool,
+    omitted: Vec<LexBuildError>
 }
 
 impl<TokId: TryFrom<usize>> LexParser<TokId> {
-    fn new(src: String) -> LexBuildResult<LexParser<TokId>> {
+    fn new(src: String, id_base: usize, nest_limit: Option<u32>, lenient: bool)
+        -> LexBuildResult<LexParser<TokId>>
+    {
         let mut p = LexParser{
             src,
             newlines: vec![0],
-            rules   : Vec::new()
+            rules   : Vec::new(),
+            pending_doc: None,
+            pending_category: None,
+            pending_error: None,
+            id_base,
+            nest_limit,
+            lenient,
+            omitted: Vec::new()
         };
         try!(p.parse());
         Ok(p)
@@ -95,6 +122,13 @@ impl<TokId: TryFrom<usize>> LexParser<TokId> {
     fn parse_declarations(&mut self, mut i: usize) -> LexBuildResult<usize> {
         i = try!(self.parse_ws(i));
         if let Some(j) = self.lookahead_is("%%", i) { return Ok(j); }
+        // A file with no `%%` line at all consists entirely of rules. If there is a `%%` line
+        // later on, then whatever precedes it must be (unsupported) declarations.
+        let has_separator = self.src.lines().any(|l| l.trim_start_matches([' ', '\t'])
+                                                      .starts_with("%%"));
+        if i < self.src.len() && !has_separator {
+            return Ok(i);
+        }
         if i < self.src.len() {
             Err(self.mk_error(LexErrorKind::UnknownDeclaration, i))
         }
@@ -108,11 +142,55 @@ impl<TokId: TryFrom<usize>> LexParser<TokId> {
             i = try!(self.parse_ws(i));
             if i == self.src.len() { break; }
             if self.lookahead_is("%%", i).is_some() { break; }
+            if self.lookahead_is("%doc ", i).is_some() {
+                let (arg, j) = self.parse_directive(i, "%doc ", self.pending_doc.is_some())?;
+                self.pending_doc = Some((arg, i));
+                i = j;
+                continue;
+            }
+            if self.lookahead_is("%category ", i).is_some() {
+                let (arg, j) = self.parse_directive(i, "%category ",
+                                                    self.pending_category.is_some())?;
+                self.pending_category = Some((arg, i));
+                i = j;
+                continue;
+            }
+            if self.lookahead_is("%error ", i).is_some() {
+                let (arg, j) = self.parse_directive(i, "%error ", self.pending_error.is_some())?;
+                self.pending_error = Some((arg, i));
+                i = j;
+                continue;
+            }
             i = try!(self.parse_rule(i));
         }
+        if let Some((_, off)) = self.pending_doc
+                                    .as_ref()
+                                    .or(self.pending_category.as_ref())
+                                    .or(self.pending_error.as_ref()) {
+            return Err(self.mk_error(LexErrorKind::InvalidDirective, *off));
+        }
         Ok(i)
     }
 
+    /// Parse a directive `name` (e.g. `%doc `) with a quoted argument, which applies to the rule
+    /// which immediately follows it, returning the argument and the offset after the directive.
+    /// `pending` is `true` if the same directive has already been given for that rule.
+    fn parse_directive(&self, i: usize, name: &str, pending: bool)
+                    -> LexBuildResult<(String, usize)>
+    {
+        let line_len = self.src[i..]
+                           .find('\n')
+                           .unwrap_or(self.src.len() - i);
+        let arg = self.src[i + name.len()..i + line_len].trim();
+        if pending
+           || arg.len() < 2
+           || !(   (arg.starts_with('\'') && arg.ends_with('\''))
+                || (arg.starts_with('"') && arg.ends_with('"'))) {
+            return Err(self.mk_error(LexErrorKind::InvalidDirective, i));
+        }
+        Ok((arg[1..arg.len() - 1].to_string(), i + line_len))
+    }
+
     fn parse_rule(&mut self, i: usize) -> LexBuildResult<usize> {
         let line_len = self.src[i..]
                            .find(|c| c == '\n')
@@ -142,12 +220,37 @@ impl<TokId: TryFrom<usize>> LexParser<TokId> {
         }
 
         let re_str = line[..rspace].trim_right().to_string();
-        let rules_len = self.rules.len();
+        let rules_len = self.id_base + self.rules.len();
         let tok_id = TokId::try_from(rules_len)
                            .unwrap_or_else(|_| panic!("TokId::try_from failed on {} (if TokId is an unsigned integer type, this probably means that {} exceeds the type's maximum value)", rules_len, rules_len));
 
-        let rule = Rule::new(Some(tok_id), name, re_str)
-                        .map_err(|_| self.mk_error(LexErrorKind::RegexError, i))?;
+        let rule = Rule::new_with_nest_limit(Some(tok_id), name, re_str, self.nest_limit)
+                        .map_err(|e| {
+                            // regex 0.2 only exposes syntax errors as strings, so this is the
+                            // only way of distinguishing an exceeded nesting limit.
+                            if e.to_string().contains("maximum number of nested") {
+                                let limit = self.nest_limit.unwrap_or(DEFAULT_NEST_LIMIT);
+                                self.mk_error(LexErrorKind::RegexNestLimit(limit), i)
+                            } else {
+                                self.mk_error(LexErrorKind::RegexError, i)
+                            }
+                        });
+        let mut rule = match rule {
+            Ok(rule) => rule,
+            Err(e) => {
+                if !self.lenient {
+                    return Err(e);
+                }
+                self.omitted.push(e);
+                self.pending_doc = None;
+                self.pending_category = None;
+                self.pending_error = None;
+                return Ok(i + line_len);
+            }
+        };
+        rule.doc = self.pending_doc.take().map(|(d, _)| d);
+        rule.category = self.pending_category.take().map(|(c, _)| c);
+        rule.error = self.pending_error.take().map(|(e, _)| e);
         self.rules.push(rule);
         Ok(i + line_len)
     }
@@ -176,7 +279,32 @@ impl<TokId: TryFrom<usize>> LexParser<TokId> {
 }
 
 pub fn parse_lex<TokId: Copy + Eq + TryFrom<usize>>(s: &str) -> LexBuildResult<LexerDef<TokId>> {
-    LexParser::new(s.to_string()).map(|p| LexerDef::new(p.rules))
+    parse_lex_with_id_base(s, 0)
+}
+
+/// As `parse_lex`, but the rules are given the consecutive IDs `id_base`, `id_base + 1`, ...
+pub fn parse_lex_with_id_base<TokId>(s: &str, id_base: usize) -> LexBuildResult<LexerDef<TokId>>
+                           where TokId: Copy + Eq + TryFrom<usize>
+{
+    parse_lex_with_nest_limit(s, id_base, None)
+}
+
+/// As `parse_lex_with_id_base`, but if `nest_limit` is `Some`, rules' regexes are compiled with
+/// that nesting limit rather than the `regex` crate's default.
+pub fn parse_lex_with_nest_limit<TokId>(s: &str, id_base: usize, nest_limit: Option<u32>)
+                                     -> LexBuildResult<LexerDef<TokId>>
+                              where TokId: Copy + Eq + TryFrom<usize>
+{
+    LexParser::new(s.to_string(), id_base, nest_limit, false).map(|p| LexerDef::new(p.rules))
+}
+
+/// As `parse_lex`, but rules whose regexes fail to compile are omitted from the resulting
+/// `LexerDef` rather than causing parsing to fail: their errors are returned alongside it, in
+/// the order the rules appear in `s`.
+pub fn parse_lex_lenient<TokId>(s: &str) -> LexBuildResult<(LexerDef<TokId>, Vec<LexBuildError>)>
+                      where TokId: Copy + Eq + TryFrom<usize>
+{
+    LexParser::new(s.to_string(), 0, None, true).map(|p| (LexerDef::new(p.rules), p.omitted))
 }
 
 #[cfg(test)]
@@ -198,6 +326,34 @@ mod test {
         assert!(parse_lex::<u8>(&src).is_ok());
     }
 
+    #[test]
+    fn test_no_separator() {
+        let src = "[0-9]+ 'int'
+[ ] ;".to_string();
+        let ast = parse_lex::<u8>(&src).unwrap();
+        assert_eq!(ast.get_rule_by_name("int").unwrap().re_str, "[0-9]+");
+        assert_eq!(ast.iter_rules().count(), 2);
+
+        let src = "
+%doc 'An integer'
+[0-9]+ 'int'".to_string();
+        let ast = parse_lex::<u8>(&src).unwrap();
+        assert_eq!(ast.get_rule_by_name("int").unwrap().doc, Some("An integer".to_string()));
+
+        // With a `%%` line, the normal split applies, so anything before it is a declaration.
+        let src = "[0-9]+ 'int'
+%%
+[ ] ;".to_string();
+        match parse_lex::<u8>(&src) {
+            Ok(_) => panic!("Declarations accepted"),
+            Err(LexBuildError{kind: LexErrorKind::UnknownDeclaration, line: 1, col: 1}) => (),
+            Err(e) => panic!("Incorrect error returned {}", e)
+        }
+        let src = "[0-9]+ 'int'
+  %%".to_string();
+        assert!(parse_lex::<u8>(&src).is_err());
+    }
+
     #[test]
     fn test_rules() {
         let src = "%%
@@ -277,6 +433,36 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_lenient() {
+        let src = "%%
+[0-9]+ 'int'
+%doc 'Broken'
+[a-z 'id'
+[ ] ;
+".to_string();
+        match parse_lex::<u8>(&src) {
+            Err(LexBuildError{kind: LexErrorKind::RegexError, line: 4, col: 1}) => (),
+            _ => panic!()
+        }
+        let (ast, omitted) = parse_lex_lenient::<u8>(&src).unwrap();
+        match omitted.as_slice() {
+            &[LexBuildError{kind: LexErrorKind::RegexError, line: 4, col: 1}] => (),
+            _ => panic!()
+        }
+        assert!(ast.get_rule_by_name("id").is_none());
+        let intrule = ast.get_rule(0).unwrap();
+        assert_eq!("int", intrule.name.as_ref().unwrap());
+        assert_eq!(intrule.tok_id, Some(0));
+        let wsrule = ast.get_rule(1).unwrap();
+        assert!(wsrule.name.is_none());
+        assert_eq!(wsrule.doc, None);
+        assert_eq!(wsrule.tok_id, Some(1));
+
+        // Errors other than those from compiling regexes are still fatal.
+        assert!(parse_lex_lenient::<u8>("%%\n[0-9]+ int\n").is_err());
+    }
+
     #[test]
     fn test_duplicate_rule() {
         let src = "%%
@@ -289,6 +475,115 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_doc() {
+        let src = "%%
+%doc 'An integer'
+[0-9]+ 'int'
+[a-z]+ 'id'
+%doc \"Plus\"
+\\+ '+'
+".to_string();
+        let ast = parse_lex::<u8>(&src).unwrap();
+        assert_eq!(ast.get_rule_by_name("int").unwrap().doc, Some("An integer".to_string()));
+        assert_eq!(ast.get_rule_by_name("id").unwrap().doc, None);
+        assert_eq!(ast.get_rule_by_name("+").unwrap().doc, Some("Plus".to_string()));
+    }
+
+    #[test]
+    fn test_broken_doc() {
+        for src in &["%%\n%doc An integer\n[0-9]+ 'int'",
+                     "%%\n%doc 'a'\n%doc 'b'\n[0-9]+ 'int'"] {
+            match parse_lex::<u8>(src) {
+                Ok(_)  => panic!("Broken doc parsed"),
+                Err(LexBuildError{kind: LexErrorKind::InvalidDirective, line: 2, col: 1}) => (),
+                Err(LexBuildError{kind: LexErrorKind::InvalidDirective, line: 3, col: 1}) => (),
+                Err(e) => panic!("Incorrect error returned {}", e)
+            }
+        }
+        match parse_lex::<u8>("%%\n[0-9]+ 'int'\n%doc 'a'\n") {
+            Ok(_)  => panic!("Dangling doc parsed"),
+            Err(LexBuildError{kind: LexErrorKind::InvalidDirective, line: 3, col: 1}) => (),
+            Err(e) => panic!("Incorrect error returned {}", e)
+        }
+    }
+
+    #[test]
+    fn test_category() {
+        let src = "%%
+%category 'keyword'
+%doc 'If'
+if 'IF'
+[a-z]+ 'ID'
+%category \"comment\"
+#[^\\n]* ;
+".to_string();
+        let ast = parse_lex::<u8>(&src).unwrap();
+        assert_eq!(ast.get_rule_by_name("IF").unwrap().category, Some("keyword".to_string()));
+        assert_eq!(ast.get_rule_by_name("IF").unwrap().doc, Some("If".to_string()));
+        assert_eq!(ast.get_rule_by_name("ID").unwrap().category, None);
+        assert_eq!(ast.get_rule(2).unwrap().category, Some("comment".to_string()));
+
+        for src in &["%%\n%category 'a'\n%category 'b'\n[0-9]+ 'int'",
+                     "%%\n[0-9]+ 'int'\n%category 'a'\n"] {
+            match parse_lex::<u8>(src) {
+                Ok(_)  => panic!("Broken category parsed"),
+                Err(LexBuildError{kind: LexErrorKind::InvalidDirective, line: 3, col: 1}) => (),
+                Err(e) => panic!("Incorrect error returned {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_directive() {
+        let src = "%%
+[a-z]+ 'ID'
+%error \"unterminated string literal\"
+\"[^\"]* 'BADSTR'
+".to_string();
+        let ast = parse_lex::<u8>(&src).unwrap();
+        assert_eq!(ast.get_rule_by_name("ID").unwrap().error, None);
+        assert_eq!(ast.get_rule_by_name("BADSTR").unwrap().error,
+                   Some("unterminated string literal".to_string()));
+
+        for src in &["%%\n%error 'a'\n%error 'b'\n[0-9]+ 'int'",
+                     "%%\n[0-9]+ 'int'\n%error 'a'\n"] {
+            match parse_lex::<u8>(src) {
+                Ok(_)  => panic!("Broken error directive parsed"),
+                Err(LexBuildError{kind: LexErrorKind::InvalidDirective, line: 3, col: 1}) => (),
+                Err(e) => panic!("Incorrect error returned {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn test_id_base() {
+        let src = "%%
+[0-9]+ 'int'
+[ ] ;
+[a-z]+ 'id'".to_string();
+        let ast = parse_lex_with_id_base::<u8>(&src, 100).unwrap();
+        assert_eq!(ast.iter_rules().map(|r| r.tok_id.unwrap()).collect::<Vec<_>>(),
+                   vec![100, 101, 102]);
+    }
+
+    #[test]
+    fn test_nest_limit() {
+        let src = format!("%%\n{}a{} 'a'", "(".repeat(300), ")".repeat(300));
+        match parse_lex::<u8>(&src) {
+            Ok(_) => panic!("Nest limit not enforced"),
+            Err(LexBuildError{kind: LexErrorKind::RegexNestLimit(250), line: 2, col: 1}) => (),
+            Err(e) => panic!("Incorrect error returned {}", e)
+        }
+        match parse_lex_with_nest_limit::<u8>(&src, 0, Some(100)) {
+            Ok(_) => panic!("Nest limit not enforced"),
+            Err(LexBuildError{kind: LexErrorKind::RegexNestLimit(100), line: 2, col: 1}) => (),
+            Err(e) => panic!("Incorrect error returned {}", e)
+        }
+        let ast = parse_lex_with_nest_limit::<u8>(&src, 0, Some(1000)).unwrap();
+        assert!(ast.get_rule_by_name("a").unwrap().re.is_match("a"));
+    }
+
     #[test]
     #[should_panic]
     fn exceed_tok_id_capacity() {
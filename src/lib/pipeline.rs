@@ -0,0 +1,217 @@
+// Copyright (c) 2017 King's College London
+// created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any person obtaining a
+// copy of this software, associated documentation and/or data (collectively the "Software"), free
+// of charge and under any and all copyright rights in the Software, and any and all patent rights
+// owned or freely licensable by each licensor hereunder covering either (i) the unmodified
+// Software as contributed to or provided by such licensor, or (ii) the Larger Works (as defined
+// below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software is contributed
+// by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create derivative works
+// of, display, perform, and distribute the Software and make, use, sell, offer for sale, import,
+// export, have made, and have sold the Software and the Larger Work(s), and to sublicense the
+// foregoing rights on either these or other terms.
+//
+// This license is subject to the following condition: The above copyright notice and either this
+// complete permission notice or at a minimum a reference to the UPL must be included in all copies
+// or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+// Multi-stage lexing. Each stage lexes the previous stage's text with a `LexerDef`, rewrites some
+// of its lexemes, and records, for each piece of its output, the span of input it came from. A
+// piece is either copied verbatim (trivia and unchanged lexemes), in which case offsets map
+// one-to-one, or is a replacement, in which case every offset within it maps to the whole of the
+// replaced lexeme. Spans are mapped back to the original source by applying each stage's pieces
+// in reverse order.
+
+use lexer::{LexError, LexerDef, Lexeme, Segment};
+
+/// A piece of a stage's output: `len` bytes starting at `start` which were produced from the
+/// `in_len` bytes starting at `in_start` of the stage's input.
+#[derive(Clone, Copy, Debug)]
+struct Piece {
+    start: usize,
+    len: usize,
+    in_start: usize,
+    in_len: usize,
+    verbatim: bool
+}
+
+/// A pipeline of lexing stages, for preprocessor-style architectures (e.g. macro expansion
+/// followed by real lexing). A pipeline starts with the original source text; each call to
+/// [`stage`](#method.stage) lexes the current text and transforms its lexemes into new text.
+/// The final text can then be lexed as normal, with
+/// [`orig_span`](#method.orig_span) mapping lexemes' spans back to the original source.
+#[derive(Debug)]
+pub struct Pipeline {
+    text: String,
+    src_len: usize,
+    /// The pieces of each stage's output, in stage order.
+    stages: Vec<Vec<Piece>>
+}
+
+impl Pipeline {
+    /// Create a new pipeline whose text is `src`.
+    pub fn new(src: &str) -> Pipeline {
+        Pipeline{text: src.to_owned(), src_len: src.len(), stages: Vec::new()}
+    }
+
+    /// The pipeline's current text (i.e. the output of the last stage).
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Return a new pipeline whose text is the result of lexing this pipeline's text with
+    /// `lexerdef`, calling `f` with each lexeme (and its text) in turn: `None` keeps the lexeme's
+    /// text unchanged, while `Some(s)` replaces it with `s`. Input between lexemes (i.e. input
+    /// matched by unnamed rules) is kept unchanged. If this pipeline's text cannot be lexed, the
+    /// error is returned: its span can be mapped to the original source with
+    /// [`orig_span`](#method.orig_span) on this pipeline.
+    pub fn stage<TokId, F>(&self, lexerdef: &LexerDef<TokId>, mut f: F)
+                        -> Result<Pipeline, LexError<TokId>>
+                     where TokId: Copy + Eq,
+                           F: FnMut(Lexeme<TokId>, &str) -> Option<String>
+    {
+        let mut text = String::with_capacity(self.text.len());
+        let mut pieces = Vec::new();
+        for seg in lexerdef.lexer(&self.text).segments()? {
+            let (in_start, in_len, repl) = match seg {
+                Segment::Trivia(start, len) => (start, len, None),
+                Segment::Token(l) => {
+                    (l.start(), l.len(), f(l, &self.text[l.start()..l.start() + l.len()]))
+                }
+            };
+            let start = text.len();
+            let verbatim = repl.is_none();
+            match repl {
+                Some(s) => text.push_str(&s),
+                None => text.push_str(&self.text[in_start..in_start + in_len])
+            }
+            pieces.push(Piece{start, len: text.len() - start, in_start, in_len, verbatim});
+        }
+        let mut stages = self.stages.clone();
+        stages.push(pieces);
+        Ok(Pipeline{text, src_len: self.src_len, stages})
+    }
+
+    /// Map the span of `len` bytes starting at byte offset `start` in the current text (e.g. a
+    /// lexeme's `start()` and `len()`) back to a `(start, len)` span in the original source.
+    /// Text copied verbatim through every stage maps exactly; text within a replacement maps to
+    /// the whole of the lexeme it replaced, so a span which overlaps a replacement covers at least
+    /// all of that lexeme's source.
+    pub fn orig_span(&self, start: usize, len: usize) -> (usize, usize) {
+        let (mut start, mut end) = (start, start + len);
+        for (i, pieces) in self.stages.iter().enumerate().rev() {
+            let in_len = match i {
+                0 => self.src_len,
+                _ => self.stages[i - 1].last().map_or(0, |p| p.start + p.len)
+            };
+            let new_start = map_start(pieces, start, in_len);
+            end = map_end(pieces, end, in_len).max(new_start);
+            start = new_start;
+        }
+        (start, end - start)
+    }
+}
+
+/// Map the offset `off` in a stage's output to the offset in its input (of length `in_len`) at
+/// which a span starting at `off` starts.
+fn map_start(pieces: &[Piece], off: usize, in_len: usize) -> usize {
+    match pieces.iter().find(|p| off < p.start + p.len) {
+        Some(p) if p.verbatim => p.in_start + (off - p.start),
+        Some(p) => p.in_start,
+        None => in_len
+    }
+}
+
+/// Map the offset `off` in a stage's output to the offset in its input at which a span ending
+/// (exclusively) at `off` ends.
+fn map_end(pieces: &[Piece], off: usize, in_len: usize) -> usize {
+    if off == 0 {
+        return 0;
+    }
+    match pieces.iter().find(|p| off <= p.start + p.len && p.len > 0) {
+        Some(p) if p.verbatim => p.in_start + (off - p.start),
+        Some(p) => p.in_start + p.in_len,
+        None => in_len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use parser::parse_lex;
+
+    #[test]
+    fn test_pipeline() {
+        // A "macro expansion" stage which replaces `$SQ` by a parenthesised expression.
+        let mut macros = parse_lex::<u8>("%%
+\\$[A-Z]+ 'MACRO'
+[^$]+ ;").unwrap();
+        let mut map = HashMap::new();
+        map.insert("MACRO", 0);
+        assert_eq!(macros.set_rule_ids(&map), (None, None));
+
+        let mut real = parse_lex::<u8>("%%
+[a-z]+ 'ID'
+\\* 'MUL'
+\\( 'LBRACK'
+\\) 'RBRACK'
+[ ] ;").unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("MUL", 1);
+        map.insert("LBRACK", 2);
+        map.insert("RBRACK", 3);
+        assert_eq!(real.set_rule_ids(&map), (None, None));
+
+        let src = "a $SQ b";
+        let p = Pipeline::new(src)
+                         .stage(&macros, |_, t| match t {
+                             "$SQ" => Some("(x * x)".to_owned()),
+                             _ => None
+                         })
+                         .unwrap();
+        assert_eq!(p.text(), "a (x * x) b");
+        let lexer = real.lexer(p.text());
+        let spans = lexer.lexemes()
+                         .unwrap()
+                         .iter()
+                         .map(|l| p.orig_span(l.start(), l.len()))
+                         .collect::<Vec<_>>();
+        assert_eq!(spans, vec![(0, 1), (2, 3), (2, 3), (2, 3), (2, 3), (2, 3), (6, 1)]);
+        assert_eq!(p.orig_span(0, p.text().len()), (0, src.len()));
+
+        // A second stage composes with the first.
+        let p = p.stage(&real, |_, t| match t {
+                      "b" => Some("bb".to_owned()),
+                      _ => None
+                  })
+                 .unwrap();
+        assert_eq!(p.text(), "a (x * x) bb");
+        assert_eq!(p.orig_span(10, 2), (6, 1));
+        assert_eq!(p.orig_span(0, 1), (0, 1));
+
+        // Errors are reported in terms of the current text.
+        let p = Pipeline::new("a $SQ b!").stage(&macros, |_, _| Some("x".to_owned())).unwrap();
+        let e = p.stage(&real, |_, _| None).unwrap_err();
+        assert_eq!(e.idx(), 5);
+        assert_eq!(p.orig_span(e.idx(), e.len()), (7, 1));
+    }
+}
@@ -0,0 +1,495 @@
+// Copyright (c) 2017 King's College London
+// created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any person obtaining a
+// copy of this software, associated documentation and/or data (collectively the "Software"), free
+// of charge and under any and all copyright rights in the Software, and any and all patent rights
+// owned or freely licensable by each licensor hereunder covering either (i) the unmodified
+// Software as contributed to or provided by such licensor, or (ii) the Larger Works (as defined
+// below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software is contributed
+// by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create derivative works
+// of, display, perform, and distribute the Software and make, use, sell, offer for sale, import,
+// export, have made, and have sold the Software and the Larger Work(s), and to sublicense the
+// foregoing rights on either these or other terms.
+//
+// This license is subject to the following condition: The above copyright notice and either this
+// complete permission notice or at a minimum a reference to the UPL must be included in all copies
+// or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use std::char;
+use std::fmt::Debug;
+
+use typename::TypeName;
+
+use lexer::{LexerDef, Rule};
+
+/// A single character of input which a simple regex must match.
+#[derive(Debug, PartialEq)]
+enum Atom {
+    /// Exactly this character.
+    Char(char),
+    /// Any character in (or, if the `bool` is `true`, not in) these inclusive ranges.
+    Class(bool, Vec<(char, char)>)
+}
+
+/// A regex which is simple enough to be matched by a hand-rolled scanner: a sequence of atoms,
+/// each optionally followed by a quantifier (`?`, `*`, or `+`), where every quantified atom other
+/// than the last is followed by an unquantified (or `+`) atom which is disjoint from it (e.g.
+/// `"[^"]*"`). Matching each atom greedily without backtracking then gives the same results as
+/// the regex engine.
+#[derive(Debug, PartialEq)]
+struct Simple {
+    atoms: Vec<(Atom, Option<char>)>
+}
+
+impl Simple {
+    /// If this regex only matches a single string (i.e. it is a sequence of unquantified
+    /// characters), return that string, or `None` otherwise.
+    fn literal(&self) -> Option<String> {
+        self.atoms
+            .iter()
+            .map(|a| match *a {
+                         (Atom::Char(c), None) => Some(c),
+                         _ => None
+                     })
+            .collect()
+    }
+}
+
+/// Characters which have a special meaning in a regex outside a character class.
+const META_CHARS: &str = "\\.+*?()|[]{}^$";
+/// Characters which the regex crate allows to be escaped with a backslash.
+const ESCAPABLE_CHARS: &str = "\\.+*?()|[]{}^$#&-~";
+
+/// If `re` is a [`Simple`](struct.Simple.html) regex, return it, or `None` otherwise.
+fn parse_simple(re: &str) -> Option<Simple> {
+    let cs = re.chars().collect::<Vec<_>>();
+    let mut atoms: Vec<(Atom, Option<char>)> = Vec::new();
+    let mut i = 0;
+    while i < cs.len() {
+        match cs[i] {
+            '[' => {
+                let (a, j) = parse_class(&cs, i + 1)?;
+                atoms.push((a, None));
+                i = j;
+            },
+            '\\' => {
+                atoms.push((Atom::Char(parse_escape(&cs, i + 1)?), None));
+                i += 2;
+            },
+            '?' | '*' | '+' => {
+                match atoms.last_mut() {
+                    Some(&mut (_, ref mut q @ None)) => *q = Some(cs[i]),
+                    _ => return None
+                }
+                i += 1;
+            },
+            c if META_CHARS.contains(c) => return None,
+            c => {
+                atoms.push((Atom::Char(c), None));
+                i += 1;
+            }
+        }
+    }
+    if atoms.is_empty() {
+        return None;
+    }
+    for w in atoms.windows(2) {
+        match (&w[0], &w[1]) {
+            (&(_, None), _) => (),
+            (&(ref a, Some(_)), &(ref b, None)) | (&(ref a, Some(_)), &(ref b, Some('+')))
+                if disjoint(a, b) => (),
+            _ => return None
+        }
+    }
+    Some(Simple{atoms})
+}
+
+/// If `re` matches exactly one, non-empty, string (i.e. it is a sequence of literal characters,
+/// simple escapes such as `\+`, and single-character classes such as `[ ]`), return that string,
+/// or `None` otherwise.
+pub(crate) fn literal(re: &str) -> Option<String> {
+    parse_simple(re)?.atoms
+                     .iter()
+                     .map(|a| match *a {
+                         (Atom::Char(c), None) => Some(c),
+                         (Atom::Class(false, ref rs), None)
+                             if rs.len() == 1 && rs[0].0 == rs[0].1 => Some(rs[0].0),
+                         _ => None
+                     })
+                     .collect()
+}
+
+/// Parse the escape sequence whose character (after the `\`) is at `cs[i]`, returning the
+/// character it denotes, or `None` if it is not a simple character escape.
+fn parse_escape(cs: &[char], i: usize) -> Option<char> {
+    match cs.get(i) {
+        Some(&'t') => Some('\t'),
+        Some(&'n') => Some('\n'),
+        Some(&'r') => Some('\r'),
+        Some(&c) if ESCAPABLE_CHARS.contains(c) => Some(c),
+        _ => None
+    }
+}
+
+/// Parse the character class whose contents start at `cs[i]` (i.e. just after the `[`),
+/// returning it and the index just after its closing `]`, or `None` if it is not a simple class
+/// (e.g. it uses named classes or nested sets).
+fn parse_class(cs: &[char], mut i: usize) -> Option<(Atom, usize)> {
+    let negated = cs.get(i) == Some(&'^');
+    if negated {
+        i += 1;
+    }
+    let mut ranges = Vec::new();
+    loop {
+        // Doubled `&`, `-`, or `~` are set operations, which we don't support.
+        if cs.get(i) == cs.get(i + 1) && cs.get(i).is_some_and(|&c| "&-~".contains(c)) {
+            return None;
+        }
+        let lo = match cs.get(i) {
+            Some(&']') if !ranges.is_empty() => break,
+            Some(&'\\') => {
+                i += 1;
+                parse_escape(cs, i)?
+            },
+            Some(&'[') | Some(&']') | None => return None,
+            Some(&c) => c
+        };
+        i += 1;
+        if cs.get(i) == Some(&'-') && cs.get(i + 1).is_some_and(|&c| c != ']') {
+            let hi = match cs[i + 1] {
+                '\\' => {
+                    i += 1;
+                    parse_escape(cs, i + 1)?
+                },
+                '[' => return None,
+                c => c
+            };
+            if hi < lo {
+                return None;
+            }
+            ranges.push((lo, hi));
+            i += 2;
+        } else {
+            ranges.push((lo, lo));
+        }
+    }
+    Some((Atom::Class(negated, ranges), i + 1))
+}
+
+/// Return the inclusive ranges of characters matched by `a`, and whether they are negated.
+fn atom_ranges(a: &Atom) -> (bool, Vec<(char, char)>) {
+    match *a {
+        Atom::Char(c) => (false, vec![(c, c)]),
+        Atom::Class(negated, ref ranges) => (negated, ranges.clone())
+    }
+}
+
+/// Is it certain that no character matches both `a` and `b`? This is conservative: it may
+/// return `false` for some disjoint atoms.
+fn disjoint(a: &Atom, b: &Atom) -> bool {
+    // Is the range `(lo, hi)` entirely within one of `ranges`?
+    let covered = |ranges: &[(char, char)], (lo, hi): (char, char)| {
+        ranges.iter().any(|&(x, y)| x <= lo && hi <= y)
+    };
+    match (atom_ranges(a), atom_ranges(b)) {
+        ((false, ar), (false, br)) => {
+            ar.iter().all(|&(lo, hi)| br.iter().all(|&(x, y)| hi < x || y < lo))
+        },
+        ((false, ar), (true, br)) => ar.iter().all(|&r| covered(&br, r)),
+        ((true, ar), (false, br)) => br.iter().all(|&r| covered(&ar, r)),
+        ((true, _), (true, _)) => false
+    }
+}
+
+/// Return a Rust expression which is `true` iff the `char` variable `c` matches `a`.
+fn atom_pred(a: &Atom) -> String {
+    match *a {
+        Atom::Char(x) => format!("c == {:?}", x),
+        Atom::Class(negated, ref ranges) => {
+            let pats = ranges.iter()
+                             .map(|&(lo, hi)| {
+                                 if lo == hi {
+                                     format!("{:?}", lo)
+                                 } else {
+                                     format!("{:?}..={:?}", lo, hi)
+                                 }
+                             })
+                             .collect::<Vec<_>>()
+                             .join(" | ");
+            format!("match c {{ {} => {}, _ => {} }}", pats, !negated, negated)
+        }
+    }
+}
+
+/// Pretty print code which matches the next character of the iterator `cs` against `a`, adding
+/// its length to `len` if it matches, or returning 0 otherwise, into `outs`.
+fn rust_pp_atom(outs: &mut String, a: &Atom, quant: Option<char>) {
+    let pred = atom_pred(a);
+    if quant.is_none() || quant == Some('+') {
+        outs.push_str(&format!("        match t[len..].chars().next() {{
+            Some(c) if {} => len += c.len_utf8(),
+            _ => return 0
+        }}
+", pred));
+    }
+    match quant {
+        Some('?') => outs.push_str(&format!("        match t[len..].chars().next() {{
+            Some(c) if {} => len += c.len_utf8(),
+            _ => ()
+        }}
+", pred)),
+        Some(_) => outs.push_str(&format!("        while let Some(c) = t[len..].chars().next() {{
+            if !({}) {{
+                break;
+            }}
+            len += c.len_utf8();
+        }}
+", pred)),
+        None => ()
+    }
+}
+
+/// Pretty print the body of a function `fn(t: &str) -> usize` which returns the length of the
+/// match of `simple` at the start of `t` (or 0 if it does not match) into `outs`.
+fn rust_pp_simple(outs: &mut String, simple: &Simple) {
+    if let Some(lit) = simple.literal() {
+        outs.push_str(&format!("        if t.starts_with({:?}) {{ {} }} else {{ 0 }}\n",
+                               lit,
+                               lit.len()));
+        return;
+    }
+    outs.push_str("        let mut len = 0;\n");
+    for &(ref a, quant) in &simple.atoms {
+        rust_pp_atom(outs, a, quant);
+    }
+    outs.push_str("        len\n");
+}
+
+/// The canonical form of a simple regex: a sequence of (ranges, quantifier) pairs.
+type Canonical = Vec<(Vec<(u32, u32)>, Option<char>)>;
+
+/// Return a canonical form of `simple`, such that two simple regexes with the same canonical form
+/// match the same strings: each atom becomes a sorted list of disjoint, non-adjacent ranges, and
+/// `x x*` becomes `x+`.
+fn canonicalise(simple: &Simple) -> Canonical {
+    let mut out: Canonical = Vec::new();
+    for &(ref a, quant) in &simple.atoms {
+        let (negated, ranges) = atom_ranges(a);
+        let mut ranges = ranges.iter().map(|&(lo, hi)| (lo as u32, hi as u32)).collect::<Vec<_>>();
+        ranges.sort();
+        let mut merged: Vec<(u32, u32)> = Vec::new();
+        for (lo, hi) in ranges {
+            match merged.last_mut() {
+                Some(&mut (_, ref mut y)) if lo <= *y + 1 => *y = (*y).max(hi),
+                _ => merged.push((lo, hi))
+            }
+        }
+        if negated {
+            let mut comp = Vec::new();
+            let mut next = 0;
+            for (lo, hi) in merged {
+                if lo > next {
+                    comp.push((next, lo - 1));
+                }
+                next = hi + 1;
+            }
+            if next <= char::MAX as u32 {
+                comp.push((next, char::MAX as u32));
+            }
+            merged = comp;
+        }
+        match (out.last_mut(), quant) {
+            (Some(&mut (ref r, ref mut q @ None)), Some('*')) if *r == merged => *q = Some('+'),
+            _ => out.push((merged, quant))
+        }
+    }
+    out
+}
+
+impl<TokId> LexerDef<TokId> {
+    /// Return the `(earlier, later)` indices of pairs of rules which match exactly the same
+    /// strings, which usually indicates a mistake (since the later rule can then never match). This
+    /// analysis is conservative: rules with identical regexes are always detected, as are rules
+    /// whose regexes are simple enough to be matched by
+    /// [`LexerBuilder::scanner`](struct.LexerBuilder.html#method.scanner) and which are equivalent
+    /// up to the order and overlap of character class ranges (e.g. `[a-z_]+` and
+    /// `[_a-mh-z][a-z_]*`). Other equivalent rules are not detected. Pairs of rules which are
+    /// active in different [`modes`](struct.Rule.html#structfield.modes), or either of which has
+    /// a continuation, reject, or previous-token predicate, balanced delimiters, or a group (any
+    /// of which can allow the later rule to match), are never reported.
+    pub fn equivalent_rules(&self) -> Vec<(usize, usize)> {
+        let canons = self.rules
+                         .iter()
+                         .map(|r| {
+                             if r.is_pseudo() {
+                                 None
+                             } else {
+                                 parse_simple(&r.re_str).map(|s| canonicalise(&s))
+                             }
+                         })
+                         .collect::<Vec<_>>();
+        let conditional = |r: &Rule<TokId>| {
+            r.cont.is_some() || r.reject.is_some() || r.after.is_some() || r.balanced.is_some()
+                || r.group.is_some()
+        };
+        let mut pairs = Vec::new();
+        for (i, r) in self.rules.iter().enumerate() {
+            for (j, r2) in self.rules.iter().enumerate().skip(i + 1) {
+                if r.modes != r2.modes || conditional(r) || conditional(r2) {
+                    continue;
+                }
+                if r.re_str == r2.re_str
+                   || (canons[i].is_some() && canons[i] == canons[j])
+                {
+                    pairs.push((i, j));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+impl<TokId: Copy + Debug + Eq + TypeName> LexerDef<TokId> {
+    /// Pretty print a function `fn_name(s: &str) -> Result<Vec<Lexeme<TokId>>, usize>` which lexes
+    /// `s` with this `LexerDef`'s rules (as `Lexer::lexemes` would with the default match policy),
+    /// returning either its lexemes or the byte offset of the first error. If every rule is
+    /// [`Simple`](struct.Simple.html), the function is a hand-rolled scanner which does not use
+    /// the regex engine; otherwise it falls back to calling `lexerdef_fn_name()`. Every rule
+    /// must have an ID.
+    pub(crate) fn rust_pp_scanner(&self, outs: &mut String, fn_name: &str, lexerdef_fn_name: &str) {
+        outs.push_str(&format!("#[allow(dead_code, unreachable_patterns)]
+pub fn {}(s: &str) -> Result<Vec<::lrlex::Lexeme<{}>>, usize> {{
+", fn_name, TokId::type_name()));
+        let simples = self.rules.iter()
+                                .map(|r| {
+                                    if r.is_pseudo() {
+                                        None
+                                    } else {
+                                        parse_simple(&r.re_str)
+                                    }
+                                })
+                                .collect::<Option<Vec<_>>>();
+        let simples = match simples {
+            Some(s) => s,
+            None => {
+                outs.push_str(&format!("    {}().lexer(s).lexemes().map_err(|e| e.idx())\n}}\n",
+                                       lexerdef_fn_name));
+                return;
+            }
+        };
+        for (ridx, simple) in simples.iter().enumerate() {
+            // Literal matchers are a single comparison, so are worth inlining into the loop.
+            if simple.literal().is_some() {
+                outs.push_str("    #[inline]\n");
+            }
+            outs.push_str(&format!("    fn scan_r{}(t: &str) -> usize {{\n", ridx));
+            rust_pp_simple(outs, simple);
+            outs.push_str("    }\n");
+        }
+        outs.push_str("    let mut lexemes = Vec::new();
+    let mut i = 0;
+    while i < s.len() {
+        let t = &s[i..];
+        let mut best = 0;
+        let mut best_ridx = 0;
+");
+        for ridx in 0..simples.len() {
+            outs.push_str(&format!("        let len = scan_r{}(t);
+        if len > best {{
+            best = len;
+            best_ridx = {};
+        }}
+", ridx, ridx));
+        }
+        outs.push_str("        if best == 0 {
+            return Err(i);
+        }
+        match best_ridx {
+");
+        for (ridx, r) in self.rules.iter().enumerate() {
+            if r.error.is_some() {
+                outs.push_str(&format!("            {} => return Err(i),\n", ridx));
+            } else if r.name.is_some() {
+                outs.push_str(&format!("            {} => lexemes.push(::lrlex::Lexeme::new({:?}, \
+                                        i, best)),\n",
+                                       ridx,
+                                       r.tok_id.unwrap()));
+            }
+        }
+        outs.push_str("            _ => ()
+        }
+        i += best;
+    }
+    Ok(lexemes)
+}
+");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Atom, Simple, parse_simple};
+    use parser::parse_lex;
+
+    #[test]
+    fn test_parse_simple() {
+        assert_eq!(parse_simple("\\+\\+"),
+                   Some(Simple{atoms: vec![(Atom::Char('+'), None), (Atom::Char('+'), None)]}));
+        assert_eq!(parse_simple("[a-z_][a-z0-9_]*"),
+                   Some(Simple{atoms: vec![(Atom::Class(false, vec![('a', 'z'), ('_', '_')]), None),
+                                           (Atom::Class(false, vec![('a', 'z'), ('0', '9'),
+                                                                    ('_', '_')]),
+                                            Some('*'))]}));
+        assert_eq!(parse_simple("[^\\n\\]]+"),
+                   Some(Simple{atoms: vec![(Atom::Class(true, vec![('\n', '\n'), (']', ']')]),
+                                            Some('+'))]}));
+        assert_eq!(parse_simple("\"[^\"]*\""),
+                   Some(Simple{atoms: vec![(Atom::Char('"'), None),
+                                           (Atom::Class(true, vec![('"', '"')]), Some('*')),
+                                           (Atom::Char('"'), None)]}));
+        assert_eq!(parse_simple("-"), Some(Simple{atoms: vec![(Atom::Char('-'), None)]}));
+        assert!(parse_simple("[0-9]+\\.[0-9]*").is_some());
+        assert_eq!(parse_simple("[a-z]*[a-c]"), None);
+        assert_eq!(parse_simple("[0-9]*[a-z]*"), None);
+        assert_eq!(parse_simple("a**"), None);
+        assert_eq!(parse_simple("a|b"), None);
+        assert_eq!(parse_simple("\\d"), None);
+        assert_eq!(parse_simple("[[:alpha:]]"), None);
+        assert_eq!(parse_simple("."), None);
+        assert_eq!(parse_simple("[a&&b]"), None);
+        assert_eq!(parse_simple("a+"), Some(Simple{atoms: vec![(Atom::Char('a'), Some('+'))]}));
+    }
+
+    #[test]
+    fn test_equivalent_rules() {
+        let src = "%%
+[a-z_]+ 'ID'
+[0-9]+ 'INT'
+[_a-mh-z][a-z_]* 'ID2'
+[0-9]+ 'INT2'
+[^0-9] 'NOTNUM'
+[^0-45-9] 'NOTNUM2'
+a|b 'AB'
+b|a 'BA'".to_string();
+        let mut lexerdef = parse_lex::<u8>(&src).unwrap();
+        assert_eq!(lexerdef.equivalent_rules(), vec![(0, 2), (1, 3), (4, 5)]);
+        lexerdef.get_rule_by_name_mut("ID2").unwrap().modes = Some(vec![1]);
+        lexerdef.get_rule_by_name_mut("INT").unwrap().after = Some(|prev| prev.is_none());
+        assert_eq!(lexerdef.equivalent_rules(), vec![(4, 5)]);
+    }
+}
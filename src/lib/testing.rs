@@ -0,0 +1,125 @@
+// Copyright (c) 2017 King's College London
+// created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any person obtaining a
+// copy of this software, associated documentation and/or data (collectively the "Software"), free
+// of charge and under any and all copyright rights in the Software, and any and all patent rights
+// owned or freely licensable by each licensor hereunder covering either (i) the unmodified
+// Software as contributed to or provided by such licensor, or (ii) the Larger Works (as defined
+// below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software is contributed
+// by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create derivative works
+// of, display, perform, and distribute the Software and make, use, sell, offer for sale, import,
+// export, have made, and have sold the Software and the Larger Work(s), and to sublicense the
+// foregoing rights on either these or other terms.
+//
+// This license is subject to the following condition: The above copyright notice and either this
+// complete permission notice or at a minimum a reference to the UPL must be included in all copies
+// or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Helpers for testing lexers.
+
+use std::cmp;
+use std::fmt::Write;
+
+use lexer::LexerDef;
+
+/// Lex `input` with `lexerdef` and assert that it produces lexemes whose rules have exactly the
+/// names `expected`, in order. If lexing fails, or the names differ, this panics with a message
+/// listing the expected and actual names side by side (together with the text of each actual
+/// lexeme), with differing entries marked by `!`.
+pub fn assert_tokens<TokId: Copy + Eq>(lexerdef: &LexerDef<TokId>, input: &str, expected: &[&str]) {
+    if let Err(msg) = check_tokens(lexerdef, input, expected) {
+        panic!("{}", msg);
+    }
+}
+
+/// Check that lexing `input` with `lexerdef` produces lexemes named `expected`, returning a
+/// description of the differences if not.
+fn check_tokens<TokId: Copy + Eq>(lexerdef: &LexerDef<TokId>, input: &str, expected: &[&str])
+                               -> Result<(), String>
+{
+    let lexemes = lexerdef.lexer(input)
+                          .lexemes()
+                          .map_err(|e| format!("Lexing error at byte offset {}", e.idx()))?;
+    let actual = lexemes.iter()
+                        .map(|l| {
+                            let n = lexerdef.iter_rules()
+                                            .find(|r| r.tok_id == Some(l.tok_id()))
+                                            .and_then(|r| r.name.as_ref())
+                                            .map_or("<unknown>", |n| n.as_str());
+                            (n, &input[l.start()..l.start() + l.len()])
+                        })
+                        .collect::<Vec<_>>();
+    if actual.len() == expected.len() && actual.iter().zip(expected).all(|(a, e)| a.0 == *e) {
+        return Ok(());
+    }
+
+    let width = expected.iter().map(|e| e.len()).chain(Some("expected".len())).max().unwrap();
+    let mut msg = format!("Tokens differ (expected {}, got {}):\n", expected.len(), actual.len());
+    writeln!(msg, "      {:<width$}  actual", "expected", width = width).ok();
+    for i in 0..cmp::max(actual.len(), expected.len()) {
+        let e = expected.get(i).cloned();
+        let a = actual.get(i).cloned();
+        let marker = if e.is_some() && e == a.map(|(n, _)| n) { ' ' } else { '!' };
+        let a = a.map_or(String::new(), |(n, t)| format!("{} {:?}", n, t));
+        writeln!(msg, "{} {:>3} {:<width$}  {}", marker, i, e.unwrap_or(""), a, width = width)
+            .ok();
+    }
+    Err(msg)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use parser::parse_lex;
+
+    fn lexerdef() -> LexerDef<u8> {
+        let src = "%%
+[a-z]+ 'ID'
+[0-9]+ 'INT'
+[ ] ;".to_string();
+        let mut lexerdef = parse_lex(&src).unwrap();
+        let mut map = HashMap::new();
+        map.insert("ID", 0);
+        map.insert("INT", 1);
+        assert_eq!(lexerdef.set_rule_ids(&map), (None, None));
+        lexerdef
+    }
+
+    #[test]
+    fn test_assert_tokens() {
+        let lexerdef = lexerdef();
+        assert_tokens(&lexerdef, "ab 12 c", &["ID", "INT", "ID"]);
+        assert_tokens(&lexerdef, "", &[]);
+        assert_eq!(check_tokens(&lexerdef, "ab 12", &["ID", "ID", "INT"]).unwrap_err(),
+                   "Tokens differ (expected 3, got 2):
+      expected  actual
+    0 ID        ID \"ab\"
+!   1 ID        INT \"12\"
+!   2 INT       \n");
+        assert_eq!(check_tokens(&lexerdef, "ab $", &["ID"]).unwrap_err(),
+                   "Lexing error at byte offset 3");
+    }
+
+    #[test]
+    #[should_panic(expected = "Tokens differ")]
+    fn test_assert_tokens_panics() {
+        assert_tokens(&lexerdef(), "ab", &["INT"]);
+    }
+}